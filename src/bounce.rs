@@ -0,0 +1,107 @@
+//! Bounces, for posts that fail after the mail client was told they went through
+//!
+//! They're delivery status notifications, so mail clients show them like any other
+//! undelivered mail, with the original message attached.
+//!
+//! <https://www.rfc-editor.org/rfc/rfc3464>
+
+use chrono::offset::Utc;
+use mail_parser::Message;
+
+/// Writes a bounce for an email that couldn't be posted, from `reply`,
+/// the SMTP reply it would have got, ex. "554 5.0.0 Server replied 422 ..."
+pub fn bounce(id: &str, to: &str, recipients: &[String], original: &str, reply: &str) -> String {
+    let reply = reply.trim_end();
+    //The enhanced status code, ex. 5.0.0, is the second part of the reply
+    let status = reply
+        .split_whitespace()
+        .nth(1)
+        .filter(|code| code.split('.').count() == 3)
+        .unwrap_or("5.0.0");
+    let reason = reply.splitn(3, ' ').nth(2).unwrap_or(reply);
+    let boundary = format!("mop3-{id}");
+    let mut email = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@mop3>\r\n\
+         To: <{to}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {}\r\n\
+         Message-ID: <{id}@mop3>\r\n",
+        Utc::now().to_rfc2822()
+    );
+    //So clients put it in the same thread as the message that bounced
+    if let Some(message_id) = Message::parse(original.as_bytes())
+        .as_ref()
+        .and_then(|msg| msg.message_id())
+    {
+        email += &format!("In-Reply-To: <{message_id}>\r\nReferences: <{message_id}>\r\n");
+    }
+    email += &format!(
+        "Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\
+         \r\n\
+         MOP3 accepted your message, but couldn't post it to Mastodon:\r\n\
+         \r\n\
+         \x20   {reason}\r\n\
+         \r\n\
+         The message is attached, so you can send it again.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; mop3\r\n\
+         Arrival-Date: {}\r\n",
+        Utc::now().to_rfc2822()
+    );
+    for recipient in recipients {
+        email += &format!(
+            "\r\n\
+             Final-Recipient: rfc822; {recipient}\r\n\
+             Action: failed\r\n\
+             Status: {status}\r\n\
+             Diagnostic-Code: smtp; {reply}\r\n"
+        );
+    }
+    email += &format!(
+        "\r\n\
+         --{boundary}\r\n\
+         Content-Type: message/rfc822\r\n\
+         Content-Transfer-Encoding: {}\r\n\
+         \r\n\
+         {}\r\n\
+         --{boundary}--\r\n",
+        if original.is_ascii() { "7bit" } else { "8bit" },
+        original.trim_end_matches(['\r', '\n'])
+    );
+    email
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounce() {
+        let original = "From: <user@example.com>\r\nMessage-ID: <abc@example.com>\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let email = bounce(
+            "bounce.1",
+            "user@example.com",
+            &["public@mop3".to_string()],
+            original,
+            "554 5.0.0 Server replied 422 Unprocessable Entity: Validation failed\r\n",
+        );
+        let msg = Message::parse(email.as_bytes()).unwrap();
+        assert_eq!(msg.subject(), Some("Undelivered Mail Returned to Sender"));
+        assert_eq!(msg.in_reply_to().as_text_ref(), Some("abc@example.com"));
+        assert!(email.contains("Status: 5.0.0\r\n"));
+        assert!(
+            email.contains("    Server replied 422 Unprocessable Entity: Validation failed\r\n")
+        );
+        let attached = msg.attachment(1).unwrap().message().unwrap();
+        assert_eq!(attached.subject(), Some("Hi"));
+    }
+}
@@ -0,0 +1,237 @@
+//! Images downloaded for --attachment and --inline, kept on disk between sessions
+//!
+//! Each image is stored under a hash of its URL, with a small JSON file alongside saying
+//! what it is. Anything fetched too long ago is downloaded again, and the least recently
+//! used images are removed once the cache grows past its size limit.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::fetch::Attachment;
+
+/// Tells apart temp files from sessions writing the same image at the same time
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct MediaCache {
+    dir: PathBuf,
+    /// In bytes
+    max_size: u64,
+    max_age: Duration,
+    /// Roughly how many bytes of images are cached, as of the last eviction plus anything put since.
+    /// Saves reading the whole directory to know when to evict
+    used: AtomicU64,
+}
+
+/// What's known about a cached image, beyond its bytes
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    filename: String,
+    content_type: String,
+    /// Seconds since the epoch
+    fetched: u64,
+}
+
+impl MediaCache {
+    pub fn new(dir: &Path, max_size: u64, max_age: Duration) -> io::Result<MediaCache> {
+        fs::create_dir_all(dir)?;
+        Ok(MediaCache {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_age,
+            used: AtomicU64::new(0),
+        })
+    }
+
+    fn key(url: &str) -> String {
+        Sha256::digest(url.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn expired(&self, entry: &Entry) -> bool {
+        now().saturating_sub(entry.fetched) > self.max_age.as_secs()
+    }
+
+    /// Gets an image if it's cached and still fresh, marking it as recently used
+    pub fn get(&self, url: &str) -> Option<Attachment> {
+        let (entry, path) = self.lookup(url)?;
+        let data = fs::read(&path).ok()?;
+        //The modified time is what eviction goes by
+        if let Err(err) = fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            println!("Could not touch cached image {:?}: {}", path, err);
+        }
+        Some(Attachment {
+            filename: entry.filename,
+            content_type: entry.content_type,
+            data,
+        })
+    }
+
+    /// How big a cached image is, without reading it or counting it as used
+    pub fn size(&self, url: &str) -> Option<u64> {
+        let (_, path) = self.lookup(url)?;
+        Some(fs::metadata(path).ok()?.len())
+    }
+
+    //The entry for an image and where its bytes are, if it's cached and still fresh
+    fn lookup(&self, url: &str) -> Option<(Entry, PathBuf)> {
+        let key = MediaCache::key(url);
+        let entry: Entry =
+            serde_json::from_str(&fs::read_to_string(self.dir.join(key.clone() + ".json")).ok()?)
+                .ok()?;
+        if entry.url != url || self.expired(&entry) {
+            return None;
+        }
+        Some((entry, self.dir.join(key)))
+    }
+
+    /// Stores an image, replacing any older copy, and evicts if that takes the cache over its limit
+    pub fn put(&self, url: &str, attachment: &Attachment) -> io::Result<()> {
+        let key = MediaCache::key(url);
+        let entry = Entry {
+            url: url.to_string(),
+            filename: attachment.filename.clone(),
+            content_type: attachment.content_type.clone(),
+            fetched: now(),
+        };
+        //The image goes first, so an entry never points at a half written file
+        self.write(&key, &attachment.data)?;
+        self.write(&(key + ".json"), serde_json::to_string(&entry)?.as_bytes())?;
+        let len = attachment.data.len() as u64;
+        if self.used.fetch_add(len, Ordering::Relaxed) + len > self.max_size {
+            if let Err(err) = self.evict() {
+                println!("Could not clean up media cache: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(format!(
+            "{name}.{}.tmp",
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, self.dir.join(name))
+    }
+
+    /// Removes expired images, then the least recently used ones until the cache fits
+    pub fn evict(&self) -> io::Result<()> {
+        let mut images = vec![];
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let data_path = path.with_extension("");
+            let entry = fs::read_to_string(&path)
+                .ok()
+                .and_then(|json| serde_json::from_str::<Entry>(&json).ok());
+            let metadata = fs::metadata(&data_path).ok();
+            match (entry, metadata) {
+                (Some(entry), Some(metadata)) if !self.expired(&entry) => {
+                    images.push((metadata.modified()?, metadata.len(), path, data_path))
+                }
+                _ => remove(&path, &data_path),
+            }
+        }
+        images.sort_by_key(|(modified, ..)| *modified);
+        let mut size: u64 = images.iter().map(|(_, len, ..)| len).sum();
+        for (_, len, path, data_path) in images {
+            if size <= self.max_size {
+                break;
+            }
+            remove(&path, &data_path);
+            size -= len;
+        }
+        self.used.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn remove(path: &Path, data_path: &Path) {
+    for path in [path, data_path] {
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                println!("Could not remove cached image {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(data: &[u8]) -> Attachment {
+        Attachment {
+            filename: "image.png".to_string(),
+            content_type: "image/png".to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_media_cache() {
+        let dir = std::env::temp_dir().join(format!("mop3-cache-{}", std::process::id()));
+        let cache = MediaCache::new(&dir, 10, Duration::from_secs(3600)).unwrap();
+        assert!(cache.get("https://example.com/a.png").is_none());
+        cache
+            .put("https://example.com/a.png", &image(b"aaaa"))
+            .unwrap();
+        cache
+            .put("https://example.com/b.png", &image(b"bbbb"))
+            .unwrap();
+        //a was used more recently, so b is the one to go
+        let old = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(dir.join(MediaCache::key("https://example.com/b.png")))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert_eq!(cache.size("https://example.com/b.png"), Some(4));
+        assert_eq!(cache.size("https://example.com/c.png"), None);
+        let cached = cache.get("https://example.com/a.png").unwrap();
+        assert_eq!(cached.data, b"aaaa");
+        assert_eq!(cached.content_type, "image/png");
+        //Going over the limit is what evicts, not every get
+        cache
+            .put("https://example.com/c.png", &image(b"ccc"))
+            .unwrap();
+        assert!(cache.get("https://example.com/a.png").is_some());
+        assert!(cache.get("https://example.com/b.png").is_none());
+        assert!(cache.get("https://example.com/c.png").is_some());
+        assert_eq!(cache.used.load(Ordering::Relaxed), 7);
+
+        let expired = MediaCache::new(&dir, 10, Duration::ZERO).unwrap();
+        let mut entry: Entry = serde_json::from_str(
+            &fs::read_to_string(dir.join(MediaCache::key("https://example.com/a.png") + ".json"))
+                .unwrap(),
+        )
+        .unwrap();
+        entry.fetched -= 10;
+        assert!(expired.expired(&entry));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,675 @@
+//! Settings, from the command line and the config file
+//!
+//! The config file is TOML, and takes the same names as the command line flags.
+//! Accounts can be given their own token, so several people can share one server,
+//! and their own settings for how posts look, ex.
+//!
+//! ```toml
+//! address = "0.0.0.0"
+//! attachment = true
+//!
+//! [accounts."user@example.com"]
+//! token = "..."
+//! password = "hunter2"
+//! html = true
+//! ```
+//!
+//! Flags on the command line win over the account's settings, which win over the rest of the file.
+
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::{Parser, Subcommand};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{strip_cred, tls};
+
+#[derive(Parser, Debug)]
+#[command(name = "MOP3")]
+#[command(author = "Nathan Kiesman. <nkizz@tacobelllabs.net>")]
+#[command(version = "0.1")]
+#[command(about = "Mastodon to POP3 gateway", long_about = None)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file to read, defaults to $XDG_CONFIG_HOME/mop3/config.toml (~/.config/mop3/config.toml)
+    #[arg(long)]
+    config: Option<String>,
+    /// If set, mastodon account to connect to, ex. user@example.com. Otherwise, uses username from POP3/SMTP connection
+    #[arg(long)]
+    account: Option<String>,
+    /// If set with --account, mastodon token to connect with, and SMTP clients that don't log in post with it. Otherwise, uses password from POP3/SMTP connection
+    #[arg(long)]
+    token: Option<String>,
+    /// Address to listen on, defaults to localhost
+    #[arg(long)]
+    address: Option<String>,
+    /// POP3 listening port, defaults to 110
+    #[arg(long)]
+    pop3port: Option<u16>,
+    /// SMTP listening port, defaults to 25
+    #[arg(long)]
+    smtpport: Option<u16>,
+    /// Only send ASCII to clients, gracefully converts unicode
+    #[arg(long)]
+    ascii: bool,
+    /// POP3S (POP3 over TLS) listening port, defaults to 995. Only used with --cert and --key
+    #[arg(long)]
+    pop3sport: Option<u16>,
+    /// SMTPS (SMTP over TLS) listening port, defaults to 465. Only used with --cert and --key
+    #[arg(long)]
+    smtpsport: Option<u16>,
+    /// PEM certificate (chain) for TLS. With --key, turns on POP3S, SMTPS, STLS and STARTTLS
+    #[arg(long)]
+    cert: Option<String>,
+    /// PEM private key for TLS
+    #[arg(long)]
+    key: Option<String>,
+    /// Makes a self-signed certificate at --cert and --key if neither file exists, defaults to mop3-cert.pem and mop3-key.pem
+    #[arg(long)]
+    selfsigned: bool,
+    /// Lets clients log in with a password before STLS or STARTTLS when TLS is set up, for ones that can't switch
+    #[arg(long)]
+    plaintextauth: bool,
+    /// Disables SMTP, posts can only be received, not sent
+    #[arg(long)]
+    nosmtp: bool,
+    /// Enables SMTP, ignored since it is now the default
+    #[arg(long, hide = true)]
+    smtp: bool,
+    /// Enables adding images as binary attachments, don't use with --inline
+    #[arg(long)]
+    attachment: bool,
+    /// Enables inline images, don't use with --attachment
+    #[arg(long)]
+    inline: bool,
+    /// Disables HTML to text conversion, makes links look better if you're using a client that supports HTML
+    #[arg(long)]
+    html: bool,
+    /// Debug mode, currently just prints out the JSON of the timeline
+    #[arg(long)]
+    debug: bool,
+    /// Include the URL of the original post in the email
+    #[arg(long)]
+    url: bool,
+    /// Appends links in posts with a URL proxy. Does not change link text, so links still appears un-proxied. "http://frogfind.com/read.php?a=" works well.
+    #[arg(long)]
+    proxy: Option<String>,
+    /// File to remember what each client has already fetched in, defaults to mop3-state.json
+    #[arg(long)]
+    state: Option<String>,
+    /// Number of posts kept in the mailbox between sessions, defaults to 40
+    #[arg(long)]
+    window: Option<usize>,
+    /// Most posts fetched in one session when catching up on a busy timeline, defaults to 400
+    #[arg(long)]
+    maxfetch: Option<usize>,
+    /// Also fetch the posts that replies are replying to, so the whole thread is in the mailbox
+    #[arg(long)]
+    ancestors: bool,
+    /// Most images (and thread lookups) downloaded at once for each session, defaults to 8
+    #[arg(long)]
+    maxdownloads: Option<usize>,
+    /// Folder to keep downloaded images in between sessions, defaults to mop3-cache
+    #[arg(long)]
+    cachedir: Option<String>,
+    /// Most megabytes of images to keep in the cache, defaults to 100. 0 turns the cache off
+    #[arg(long)]
+    cachesize: Option<u64>,
+    /// Days before a cached image is downloaded again, defaults to 7
+    #[arg(long)]
+    cacheexpiry: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Logs in to a Mastodon account, and saves its token in the config file
+    Login {
+        /// ex. user@example.com
+        account: String,
+    },
+}
+
+impl Args {
+    /// The config file passed in, or the default one
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config
+            .as_ref()
+            .map(PathBuf::from)
+            .or_else(default_path)
+    }
+}
+
+/// Settings that can be different for each account, unset if they weren't given
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+struct Overrides {
+    ascii: Option<bool>,
+    attachment: Option<bool>,
+    inline: Option<bool>,
+    html: Option<bool>,
+    url: Option<bool>,
+    proxy: Option<String>,
+    window: Option<usize>,
+    maxfetch: Option<usize>,
+    ancestors: Option<bool>,
+}
+
+impl Overrides {
+    //Fills in anything unset from the next place down
+    fn or(&self, other: &Overrides) -> Overrides {
+        Overrides {
+            ascii: self.ascii.or(other.ascii),
+            attachment: self.attachment.or(other.attachment),
+            inline: self.inline.or(other.inline),
+            html: self.html.or(other.html),
+            url: self.url.or(other.url),
+            proxy: self.proxy.clone().or_else(|| other.proxy.clone()),
+            window: self.window.or(other.window),
+            maxfetch: self.maxfetch.or(other.maxfetch),
+            ancestors: self.ancestors.or(other.ancestors),
+        }
+    }
+
+    fn images(&self) -> bool {
+        self.attachment == Some(true) || self.inline == Some(true)
+    }
+}
+
+/// An account in the config file
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+struct AccountConfig {
+    /// The server the account is on, if it isn't the domain in its address, ex. "social.example.com"
+    instance: Option<String>,
+    token: Option<String>,
+    /// What the mail client has to log in with. If unset, it's the token
+    password: Option<String>,
+    #[serde(flatten)]
+    options: Overrides,
+}
+
+/// Who to talk to Mastodon as, once a mail client has said who it is
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Login {
+    /// ex. user@example.com
+    pub address: String,
+    /// The domain in the address, used in email addresses
+    pub domain: String,
+    /// Where the API is, ex. https://example.com
+    pub url: String,
+    pub token: String,
+}
+
+/// The config file as written
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct ConfigFile {
+    account: Option<String>,
+    token: Option<String>,
+    address: Option<String>,
+    pop3port: Option<u16>,
+    smtpport: Option<u16>,
+    pop3sport: Option<u16>,
+    smtpsport: Option<u16>,
+    cert: Option<String>,
+    key: Option<String>,
+    selfsigned: Option<bool>,
+    plaintextauth: Option<bool>,
+    nosmtp: Option<bool>,
+    debug: Option<bool>,
+    state: Option<String>,
+    maxdownloads: Option<usize>,
+    cachedir: Option<String>,
+    cachesize: Option<u64>,
+    cacheexpiry: Option<u64>,
+    #[serde(flatten)]
+    options: Overrides,
+    /// Keyed by address, ex. "user@example.com"
+    accounts: HashMap<String, AccountConfig>,
+}
+
+/// How one account's posts are fetched and rendered, with the defaults filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub ascii: bool,
+    pub attachment: bool,
+    pub inline: bool,
+    pub html: bool,
+    pub url: bool,
+    pub proxy: Option<String>,
+    pub window: usize,
+    pub maxfetch: usize,
+    pub ancestors: bool,
+}
+
+/// Everything both servers need to know, shared between every session
+pub struct Config {
+    pub account: Option<String>,
+    pub token: Option<String>,
+    pub address: String,
+    pub pop3port: u16,
+    pub smtpport: u16,
+    pub pop3sport: u16,
+    pub smtpsport: u16,
+    /// TLS is only on if both are set
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub selfsigned: bool,
+    /// Whether passwords are taken on plain connections even though they could switch to TLS
+    pub plaintextauth: bool,
+    /// The loaded certificate, None if TLS is off
+    pub tls: Option<Arc<ServerConfig>>,
+    pub nosmtp: bool,
+    pub debug: bool,
+    pub state: PathBuf,
+    pub maxdownloads: usize,
+    pub cachedir: PathBuf,
+    /// In megabytes
+    pub cachesize: u64,
+    /// In days
+    pub cacheexpiry: u64,
+    /// From the command line, which beats everything
+    cli: Overrides,
+    /// From the top of the config file
+    file: Overrides,
+    accounts: HashMap<String, AccountConfig>,
+}
+
+impl Config {
+    /// Reads the config file and puts the command line on top of it, then loads the certificate
+    pub fn load(args: Args) -> Result<Config, String> {
+        let file = match args.config_path() {
+            //The default file is optional
+            Some(path) if args.config.is_some() || path.exists() => read_file(&path)
+                .map_err(|err| format!("Could not read config file {}: {}", path.display(), err))?,
+            _ => ConfigFile::default(),
+        };
+        let mut config = Config::merge(args, file);
+        config.tls = tls::server_config(&config)?;
+        Ok(config)
+    }
+
+    fn merge(args: Args, file: ConfigFile) -> Config {
+        //A flag that wasn't passed can't tell us to turn something off, so leave it to the file
+        let flag = |set: bool| set.then_some(true);
+        let selfsigned = args.selfsigned || file.selfsigned.unwrap_or(false);
+        //Self-signing needs somewhere to put it, so it gets a default
+        let tls_path = |path: Option<String>, default: &str| {
+            path.or_else(|| selfsigned.then(|| default.to_string()))
+                .map(PathBuf::from)
+        };
+        Config {
+            account: args.account.or(file.account),
+            token: args.token.or(file.token),
+            address: args
+                .address
+                .or(file.address)
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            pop3port: args.pop3port.or(file.pop3port).unwrap_or(110),
+            smtpport: args.smtpport.or(file.smtpport).unwrap_or(25),
+            pop3sport: args.pop3sport.or(file.pop3sport).unwrap_or(995),
+            smtpsport: args.smtpsport.or(file.smtpsport).unwrap_or(465),
+            cert: tls_path(args.cert.or(file.cert), "mop3-cert.pem"),
+            key: tls_path(args.key.or(file.key), "mop3-key.pem"),
+            selfsigned,
+            plaintextauth: args.plaintextauth || file.plaintextauth.unwrap_or(false),
+            tls: None,
+            nosmtp: args.nosmtp || file.nosmtp.unwrap_or(false),
+            debug: args.debug || file.debug.unwrap_or(false),
+            state: PathBuf::from(
+                args.state
+                    .or(file.state)
+                    .unwrap_or_else(|| "mop3-state.json".to_string()),
+            ),
+            maxdownloads: args.maxdownloads.or(file.maxdownloads).unwrap_or(8),
+            cachedir: PathBuf::from(
+                args.cachedir
+                    .or(file.cachedir)
+                    .unwrap_or_else(|| "mop3-cache".to_string()),
+            ),
+            cachesize: args.cachesize.or(file.cachesize).unwrap_or(100),
+            cacheexpiry: args.cacheexpiry.or(file.cacheexpiry).unwrap_or(7),
+            cli: Overrides {
+                ascii: flag(args.ascii),
+                attachment: flag(args.attachment),
+                inline: flag(args.inline),
+                html: flag(args.html),
+                url: flag(args.url),
+                proxy: args.proxy,
+                window: args.window,
+                maxfetch: args.maxfetch,
+                ancestors: flag(args.ancestors),
+            },
+            file: file.options,
+            //Addresses are looked up without case, as mail clients don't always keep it
+            accounts: file
+                .accounts
+                .into_iter()
+                .map(|(address, account)| (address.to_lowercase(), account))
+                .collect(),
+        }
+    }
+
+    //The config file's entry for an address, whatever case it's in
+    fn account(&self, address: &str) -> Option<&AccountConfig> {
+        self.accounts.get(&address.to_lowercase())
+    }
+
+    /// The settings for an account, ex. "user@example.com"
+    pub fn options(&self, account: &str) -> Options {
+        let options = match self.account(account) {
+            Some(account) => self.cli.or(&account.options).or(&self.file),
+            None => self.cli.or(&self.file),
+        };
+        Options {
+            ascii: options.ascii.unwrap_or(false),
+            attachment: options.attachment.unwrap_or(false),
+            inline: options.inline.unwrap_or(false),
+            html: options.html.unwrap_or(false),
+            url: options.url.unwrap_or(false),
+            proxy: options.proxy,
+            window: options.window.unwrap_or(40),
+            maxfetch: options.maxfetch.unwrap_or(400),
+            ancestors: options.ancestors.unwrap_or(false),
+        }
+    }
+
+    /// Whether any account has images downloaded, and so needs the media cache
+    pub fn images(&self) -> bool {
+        self.cli.images()
+            || self.file.images()
+            || self
+                .accounts
+                .values()
+                .any(|account| account.options.images())
+    }
+
+    /// Where the API is for an account, ex. https://example.com
+    pub fn instance_url(&self, address: &str) -> String {
+        match self
+            .account(address)
+            .and_then(|account| account.instance.as_deref())
+        {
+            Some(instance) => strip_cred(instance).1.trim_end_matches('/').to_string(),
+            None => strip_cred(address).1,
+        }
+    }
+
+    /// Picks the Mastodon account and token for a mail client's username and password.
+    /// Accounts in the config file use their own token, once the password (or if there
+    /// isn't one, the token) matches.
+    /// Otherwise it's --token, but only with --account, or the password is the token.
+    /// Returns None if the password is wrong
+    pub fn login(&self, username: &str, password: &str) -> Option<Login> {
+        let address = self.account.as_deref().unwrap_or(username).to_lowercase();
+        //Without --account, the username picks the server, so --token could be sent anywhere
+        let shared = self.account.as_ref().and(self.token.clone());
+        let (domain, _) = strip_cred(&address);
+        let url = self.instance_url(&address);
+        let token = match self.account(&address) {
+            Some(account) => {
+                //Without a password, the token is the password, same as for APOP
+                let expected = account.password.as_deref().or(account.token.as_deref());
+                if expected.is_some_and(|expected| expected != password) {
+                    return None;
+                }
+                match (&account.token, &account.password) {
+                    (Some(token), _) => token.clone(),
+                    //A password of its own, but no token, so it's --token's
+                    (None, Some(_)) => shared?,
+                    //Only settings, so it logs in like any other address
+                    (None, None) => shared.unwrap_or_else(|| password.to_string()),
+                }
+            }
+            None => shared.unwrap_or_else(|| password.to_string()),
+        };
+        Some(Login {
+            address,
+            domain,
+            url,
+            token,
+        })
+    }
+
+    /// Whether an account in the config file has a password of its own
+    pub fn has_password(&self, address: &str) -> bool {
+        self.account(address)
+            .is_some_and(|account| account.password.is_some())
+    }
+
+    /// What an account's APOP digest is made with: its password, or if it hasn't got one, its token.
+    /// Only accounts in the config file have one, as APOP never sends us the token
+    pub fn apop_secret(&self, address: &str) -> Option<&str> {
+        let account = self.account(self.account.as_deref().unwrap_or(address))?;
+        account.password.as_deref().or(account.token.as_deref())
+    }
+
+    /// For SMTP clients that don't log in, which can only post as --account with --token.
+    /// The sender's address can't be trusted to pick an account's token, or the server it's sent to
+    pub fn default_login(&self) -> Option<Login> {
+        let address = self.account.as_deref()?;
+        Some(Login {
+            address: address.to_string(),
+            domain: strip_cred(address).0,
+            url: self.instance_url(address),
+            token: self.token.clone()?,
+        })
+    }
+}
+
+/// Puts an account's token, and the password for mail clients if one is given, in the config file,
+/// keeping everything else in it as it was
+pub fn save_login(
+    path: &Path,
+    address: &str,
+    token: &str,
+    password: Option<&str>,
+) -> io::Result<()> {
+    let mut doc = match fs::read_to_string(path) {
+        Ok(text) => text
+            .parse::<toml_edit::DocumentMut>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            toml_edit::DocumentMut::new()
+        }
+        Err(err) => return Err(err),
+    };
+    let accounts = doc
+        .entry("accounts")
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "accounts isn't a table"))?;
+    //Only the accounts under it get headers
+    accounts.set_implicit(true);
+    let account = accounts
+        .entry(address)
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "account isn't a table"))?;
+    account.insert("token", toml_edit::value(token));
+    if let Some(password) = password {
+        account.insert("password", toml_edit::value(password));
+    }
+    //It's a password, more or less, so it's only readable by us before the token goes in it
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, "")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::write(&tmp, doc.to_string())?;
+    fs::rename(tmp, path)
+}
+
+fn read_file(path: &Path) -> io::Result<ConfigFile> {
+    toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+//$XDG_CONFIG_HOME/mop3/config.toml, falling back to ~/.config like the spec says
+fn default_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("mop3").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_precedence() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            address = "0.0.0.0"
+            pop3port = 1110
+            html = true
+            window = 20
+
+            [accounts."user@example.com"]
+            attachment = true
+            window = 10
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["mop3", "--pop3port", "2110", "--ascii"]);
+        let config = Config::merge(args, file);
+        assert_eq!(config.address, "0.0.0.0");
+        assert_eq!(config.pop3port, 2110);
+        assert_eq!(config.smtpport, 25);
+        assert!(config.images());
+
+        let options = config.options("user@example.com");
+        assert!(options.ascii && options.html && options.attachment);
+        assert_eq!(options.window, 10);
+        let options = config.options("other@example.com");
+        assert!(!options.attachment);
+        assert_eq!(options.window, 20);
+        assert_eq!(options.maxfetch, 400);
+    }
+
+    #[test]
+    fn test_save_login() {
+        let path = env::temp_dir().join(format!("mop3-config-{}.toml", std::process::id()));
+        fs::write(&path, "# Shared server\nascii = true\n").unwrap();
+        save_login(&path, "user@example.com", "secret", Some("hunter2")).unwrap();
+        save_login(&path, "other@example.com", "secret2", None).unwrap();
+        save_login(&path, "user@example.com", "secret3", None).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# Shared server\nascii = true\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        let config = Config::merge(Args::parse_from(["mop3"]), read_file(&path).unwrap());
+        assert!(config.options("user@example.com").ascii);
+        assert_eq!(
+            config.login("user@example.com", "hunter2").unwrap().token,
+            "secret3"
+        );
+        assert_eq!(
+            config.login("other@example.com", "secret2").unwrap().token,
+            "secret2"
+        );
+        assert!(config.login("other@example.com", "").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_login() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [accounts."user@example.com"]
+            instance = "social.example.com"
+            token = "secret"
+            password = "hunter2"
+
+            [accounts."NoPassword@example.com"]
+            token = "secret2"
+
+            [accounts."tokenless@example.com"]
+            password = "letmein"
+            "#,
+        )
+        .unwrap();
+        let config = Config::merge(Args::parse_from(["mop3"]), file);
+        let login = config.login("user@example.com", "hunter2").unwrap();
+        assert_eq!(login.url, "https://social.example.com");
+        assert_eq!(login.domain, "example.com");
+        assert_eq!(login.token, "secret");
+        assert!(config.login("user@example.com", "wrong").is_none());
+        //Without a password, only the token will do
+        assert!(config.login("nopassword@example.com", "anything").is_none());
+        let login = config.login("nopassword@example.com", "secret2").unwrap();
+        assert_eq!(login.token, "secret2");
+        //Anyone else logs in with their token as the password, as before
+        let login = config.login("other@example.com", "token").unwrap();
+        assert_eq!(login.url, "https://example.com");
+        assert_eq!(login.token, "token");
+        assert!(config.default_login().is_none());
+        assert_eq!(config.apop_secret("user@example.com"), Some("hunter2"));
+        assert_eq!(config.apop_secret("other@example.com"), None);
+        //Addresses match whatever case they're in, so a different case can't skip the password
+        let login = config.login("User@Example.com", "hunter2").unwrap();
+        assert_eq!(login.address, "user@example.com");
+        assert_eq!(login.url, "https://social.example.com");
+        assert!(config.login("USER@example.com", "wrong").is_none());
+        //--account picks the config account whatever the username, and still needs its password
+        let accounts = r#"
+            [accounts."user@example.com"]
+            token = "secret"
+            password = "hunter2"
+
+            [accounts."tokenless@example.com"]
+            password = "letmein"
+            "#;
+        let with_token = |account: &str| {
+            Config::merge(
+                Args::parse_from(["mop3", "--token", "shared", "--account", account]),
+                toml::from_str(accounts).unwrap(),
+            )
+        };
+        let config = with_token("User@example.com");
+        assert!(config.login("anyone", "anything").is_none());
+        assert_eq!(config.login("anyone", "hunter2").unwrap().token, "secret");
+        //An account with a password but no token uses --token, once the password matches
+        let config = with_token("tokenless@example.com");
+        assert!(config.login("anyone", "anything").is_none());
+        assert_eq!(config.login("anyone", "letmein").unwrap().token, "shared");
+        //--token alone isn't enough to post without logging in, it needs --account to say where
+        let config = Config::merge(
+            Args::parse_from(["mop3", "--token", "shared"]),
+            ConfigFile::default(),
+        );
+        assert!(config.default_login().is_none());
+        //or to log in, as the username would pick which server gets it
+        let login = config.login("x@attacker.example", "x").unwrap();
+        assert_eq!(login.url, "https://attacker.example");
+        assert_eq!(login.token, "x");
+        let config = Config::merge(
+            Args::parse_from(["mop3", "--token", "shared", "--account", "me@example.org"]),
+            ConfigFile::default(),
+        );
+        let login = config.default_login().unwrap();
+        assert_eq!(login.address, "me@example.org");
+        assert_eq!(login.url, "https://example.org");
+        assert_eq!(login.token, "shared");
+    }
+}
@@ -0,0 +1,290 @@
+//! Getting things out of Mastodon for the POP3 side
+//!
+//! Sessions run on plain threads and block on a shared runtime whenever they need
+//! the network, so the slow parts (images, mostly) can be fetched in parallel.
+
+use std::{collections::HashMap, future::Future};
+
+use futures::{stream, StreamExt};
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use tokio::runtime::Runtime;
+
+use crate::cache::MediaCache;
+use crate::mastodon::{self, ApiError, Context};
+use crate::USER_AGENT;
+
+#[derive(Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+pub struct Fetcher {
+    runtime: Runtime,
+    client: Client,
+    /// Most requests in flight at once for one session
+    concurrency: usize,
+    cache: Option<MediaCache>,
+}
+
+impl Fetcher {
+    pub fn new(concurrency: usize, cache: Option<MediaCache>) -> Fetcher {
+        Fetcher {
+            runtime: Runtime::new().expect("Could not start async runtime"),
+            client: Client::new(),
+            concurrency: concurrency.max(1),
+            cache,
+        }
+    }
+
+    /// Runs a fetch from one of the session threads
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, url: &str, token: &str) -> Result<T, ApiError> {
+        parse(self.request(url, token).await?).await
+    }
+
+    async fn request(&self, url: &str, token: &str) -> Result<Response, ApiError> {
+        check(
+            self.client
+                .get(url)
+                .header("Authorization", "Bearer ".to_owned() + token)
+                .header("User-Agent", USER_AGENT)
+                .send()
+                .await?,
+        )
+        .await
+    }
+
+    /// Fetches everything newer than since_id, following the Link header back in time
+    /// until we've caught up (or hit max_fetch). Without a since_id, only the first page is fetched
+    pub async fn timeline<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        token: &str,
+        since_id: &str,
+        max_fetch: usize,
+        debug: bool,
+    ) -> Result<Vec<T>, ApiError> {
+        let mut posts: Vec<T> = vec![];
+        let mut next = Some(url.to_string());
+        while let Some(mut url) = next.take() {
+            //The next page links don't keep since_id, so put it back
+            if !since_id.is_empty() && !url.contains("since_id=") {
+                url = format!("{url}&since_id={since_id}");
+            }
+            let res = self.request(&url, token).await?;
+            if !since_id.is_empty() {
+                next = res
+                    .headers()
+                    .get("Link")
+                    .and_then(|link| link.to_str().ok())
+                    .and_then(next_link);
+            }
+            let timeline_str = res.text().await?;
+            if debug {
+                println!("{}", timeline_str);
+            }
+            let page: Vec<T> = serde_json::from_str(&timeline_str)
+                .map_err(|err| ApiError::Perm(format!("Server sent malformed timeline: {err}")))?;
+            if page.is_empty() {
+                break;
+            }
+            posts.extend(page);
+            if posts.len() >= max_fetch {
+                println!("Stopped fetching after {} posts", posts.len());
+                posts.truncate(max_fetch);
+                break;
+            }
+        }
+        Ok(posts)
+    }
+
+    /// Gets the posts above (ancestors) and below (descendants) a post in its thread
+    pub async fn context(
+        &self,
+        account_url: &str,
+        token: &str,
+        id: &str,
+    ) -> Result<Context, ApiError> {
+        self.get(
+            &format!("{account_url}/api/v1/statuses/{id}/context"),
+            token,
+        )
+        .await
+    }
+
+    /// Threads for several posts at once, in the same order
+    pub async fn contexts(
+        &self,
+        account_url: &str,
+        token: &str,
+        ids: &[&str],
+    ) -> Vec<Result<Context, ApiError>> {
+        stream::iter(ids)
+            .map(|id| self.context(account_url, token, id))
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    /// Gets an image from the cache, or downloads it and caches it
+    pub async fn attachment(&self, url: &str) -> Result<Attachment, ApiError> {
+        let Some(cache) = &self.cache else {
+            return self.download(url).await;
+        };
+        if let Some(attachment) = cache.get(url) {
+            return Ok(attachment);
+        }
+        let attachment = self.download(url).await?;
+        if let Err(err) = cache.put(url, &attachment) {
+            println!("Could not cache image {}: {}", url, err);
+        }
+        Ok(attachment)
+    }
+
+    /// Downloads an image, naming it after the end of its URL
+    async fn download(&self, url: &str) -> Result<Attachment, ApiError> {
+        let img = check(
+            self.client
+                .get(url)
+                .header("User-Agent", USER_AGENT)
+                .send()
+                .await?,
+        )
+        .await?;
+        let filename = url.split('/').next_back().unwrap_or("image").to_string();
+        let content_type = img
+            .headers()
+            .get("Content-Type")
+            .and_then(|mime| mime.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        Ok(Attachment {
+            filename,
+            content_type,
+            data: img.bytes().await?.to_vec(),
+        })
+    }
+
+    /// Downloads a batch of images, a few at a time
+    pub async fn attachments(
+        &self,
+        urls: Vec<String>,
+    ) -> HashMap<String, Result<Attachment, ApiError>> {
+        stream::iter(urls)
+            .map(|url| async move {
+                let attachment = self.attachment(&url).await;
+                (url, attachment)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    /// How big an image is, from the cache or a HEAD request, without downloading it.
+    /// None if the server doesn't say
+    async fn media_size(&self, url: &str) -> Option<u64> {
+        if let Some(size) = self.cache.as_ref().and_then(|cache| cache.size(url)) {
+            return Some(size);
+        }
+        let res = self
+            .client
+            .head(url)
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await
+            .ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+        //Not res.content_length(), which is always 0 for HEAD
+        res.headers()
+            .get("Content-Length")?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Sizes of a batch of images, a few at a time, in the same order
+    pub async fn media_sizes(&self, urls: &[String]) -> Vec<Option<u64>> {
+        stream::iter(urls)
+            .map(|url| self.media_size(url))
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    /// Trims the media cache back down to size, if there is one. After that, it's trimmed
+    /// whenever caching an image takes it over
+    pub fn evict_cache(&self) {
+        if let Some(cache) = &self.cache {
+            if let Err(err) = cache.evict() {
+                println!("Could not clean up media cache: {}", err);
+            }
+        }
+    }
+
+    pub async fn dismiss_notification(&self, account_url: &str, token: &str, id: &str) {
+        let res = self
+            .client
+            .post(format!("{account_url}/api/v1/notifications/{id}/dismiss"))
+            .header("Authorization", "Bearer ".to_owned() + token)
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await;
+        if let Err(err) = res.and_then(|res| res.error_for_status()) {
+            println!("Could not dismiss notification {}: {:?}", id, err);
+        }
+    }
+}
+
+async fn check(res: Response) -> Result<Response, ApiError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    Err(ApiError::from_response(
+        status,
+        &res.text().await.unwrap_or_default(),
+    ))
+}
+
+async fn parse<T: DeserializeOwned>(res: Response) -> Result<T, ApiError> {
+    let status = res.status();
+    mastodon::decode(status, &res.text().await?)
+}
+
+/// Gets the URL for the next (older) page out of a Link header, ex.
+/// `<https://example.com/api/v1/timelines/home?max_id=123>; rel="next", <...>; rel="prev"`
+fn next_link(link: &str) -> Option<String> {
+    link.split(',')
+        .find(|part| part.contains("rel=\"next\""))
+        .and_then(|part| {
+            let start = part.find('<')? + 1;
+            let end = part.find('>')?;
+            Some(part[start..end].to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_link() {
+        assert_eq!(
+            next_link("<https://example.com/api/v1/timelines/home?max_id=2>; rel=\"next\", <https://example.com/api/v1/timelines/home?min_id=5>; rel=\"prev\""),
+            Some("https://example.com/api/v1/timelines/home?max_id=2".to_string())
+        );
+        assert_eq!(
+            next_link("<https://example.com/api/v1/timelines/home?min_id=5>; rel=\"prev\""),
+            None
+        );
+    }
+}
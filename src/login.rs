@@ -0,0 +1,100 @@
+//! `mop3 login`, for getting a token without making an app by hand
+//!
+//! Registers MOP3 as an app on the server, has the user authorize it in their browser,
+//! then trades the code they paste back for a token, which goes in the config file.
+//!
+//! <https://docs.joinmastodon.org/client/token/>
+
+use std::{io, path::Path};
+
+use reqwest::blocking::Client;
+
+use crate::config::{self, Config};
+use crate::mastodon::{self, ApiError, Application, Token};
+use crate::USER_AGENT;
+
+/// Shows the code on the page instead of redirecting, so it can be pasted into the terminal
+const REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+/// Enough to read every feed, dismiss notifications, upload media and post
+const SCOPES: &str = "read write";
+
+pub fn login(config: &Config, config_path: &Path, address: &str) -> Result<(), String> {
+    let client = Client::new();
+    let url = config.instance_url(address);
+    let app = register_app(&client, &url)
+        .map_err(|err| format!("Could not register with {}: {}", url, err))?;
+
+    println!("Open this link, log in as {}, and authorize MOP3:", address);
+    println!(
+        "{url}/oauth/authorize?response_type=code&client_id={}&redirect_uri={REDIRECT_URI}&scope={}",
+        app.client_id,
+        SCOPES.replace(' ', "+")
+    );
+    println!("Then paste the code it gives you here:");
+    let mut code = String::new();
+    io::stdin()
+        .read_line(&mut code)
+        .map_err(|err| format!("Could not read code: {}", err))?;
+    let code = code.trim();
+    if code.is_empty() {
+        return Err("No code given".to_string());
+    }
+
+    let token = get_token(&client, &url, &app, code)
+        .map_err(|err| format!("Could not get token: {}", err))?;
+
+    println!("Now pick a password for your mail client to log in with, or leave it empty to keep the one you have (or use the token):");
+    let mut password = String::new();
+    io::stdin()
+        .read_line(&mut password)
+        .map_err(|err| format!("Could not read password: {}", err))?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    let password = (!password.is_empty()).then_some(password);
+
+    config::save_login(config_path, address, &token.access_token, password)
+        .map_err(|err| format!("Could not save token to {}: {}", config_path.display(), err))?;
+    println!(
+        "Logged in as {} with scopes \"{}\", token saved to {}",
+        address,
+        token.scope,
+        config_path.display()
+    );
+    if password.is_none() && !config.has_password(address) {
+        println!("No password set, so your mail client will need the token as its password");
+    }
+    Ok(())
+}
+
+//https://docs.joinmastodon.org/methods/apps/#create
+fn register_app(client: &Client, url: &str) -> Result<Application, ApiError> {
+    mastodon::parse(
+        client
+            .post(format!("{url}/api/v1/apps"))
+            .header("User-Agent", USER_AGENT)
+            .form(&[
+                ("client_name", "MOP3"),
+                ("redirect_uris", REDIRECT_URI),
+                ("scopes", SCOPES),
+                ("website", env!("CARGO_PKG_HOMEPAGE")),
+            ])
+            .send()?,
+    )
+}
+
+//https://docs.joinmastodon.org/methods/oauth/#token
+fn get_token(client: &Client, url: &str, app: &Application, code: &str) -> Result<Token, ApiError> {
+    mastodon::parse(
+        client
+            .post(format!("{url}/oauth/token"))
+            .header("User-Agent", USER_AGENT)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("client_id", &app.client_id),
+                ("client_secret", &app.client_secret),
+                ("redirect_uri", REDIRECT_URI),
+                ("scope", SCOPES),
+            ])
+            .send()?,
+    )
+}
@@ -1,7 +1,10 @@
 use std::{
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    collections::{HashMap, HashSet},
+    io::prelude::*,
+    net::TcpListener,
+    sync::{Arc, Mutex},
     thread,
+    time::Duration,
     //fs
 };
 
@@ -15,10 +18,28 @@ use html2text::from_read;
 use mail_builder::*;
 use mail_parser::*;
 use reqwest::blocking::{multipart::Form, multipart::Part, Client};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use rustls::ServerConfig;
 use string_concat::*;
 
+mod bounce;
+mod cache;
+mod config;
+mod fetch;
+mod login;
+mod mastodon;
+mod sasl;
+mod state;
+mod tls;
+use cache::MediaCache;
+use config::{Args, Command, Config, Login, Options};
+use fetch::{Attachment, Fetcher};
+use mastodon::{
+    Account, ApiError, Conversation, Item, MediaAttachment, NewStatus, Notification, SearchResults,
+    Status,
+};
+use state::{Bounce, MailboxState, PendingPost, State};
+use tls::Connection;
+
 static USER_AGENT: &str = "mop3";
 
 #[derive(Debug)]
@@ -27,29 +48,205 @@ struct Cred {
     password: String,
 }
 
-#[derive(Serialize)]
-struct Status {
-    status: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    in_reply_to_id: Option<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    media_ids: Vec<String>,
+/// How often to check on media that's still processing, and how many times before giving up
+const MEDIA_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MEDIA_POLL_ATTEMPTS: u32 = 60;
+/// Sending to an address here sets who can see a post, ex. unlisted@mop3
+const VISIBILITY_DOMAIN: &str = "mop3";
+/// Most threads one session looks up, so a big catch up doesn't run into rate limits
+/// (300 requests per 5 minutes by default) or keep the client waiting
+const THREAD_LOOKUPS: usize = 40;
+/// Roughly what each attached image adds to an email besides its data, for size estimates
+const ATTACHMENT_HEADERS: usize = 200;
+
+/// The Mastodon feed a mailbox serves, picked with a tag in the POP3 username, ex. user+local@example.com
+#[derive(Debug, PartialEq)]
+enum Feed {
+    Home,
+    Local,
+    Federated,
+    List(String),
+    Tag(String),
+    Bookmarks,
+    Favourites,
+    Notifications,
+    Direct,
 }
 
-/// Data returned from verify credentials call
-///
-/// <https://docs.joinmastodon.org/methods/accounts/#verify_credentials>
-#[derive(Deserialize)]
-struct CredentialAccount {
-    display_name: String,
-    username: String,
+impl Feed {
+    fn parse(tag: &str) -> Option<Feed> {
+        Some(match tag {
+            "home" => Feed::Home,
+            "local" => Feed::Local,
+            "federated" | "public" => Feed::Federated,
+            "bookmarks" => Feed::Bookmarks,
+            "favourites" | "favorites" => Feed::Favourites,
+            "notifications" => Feed::Notifications,
+            "direct" => Feed::Direct,
+            _ => match tag.split_once('.')? {
+                ("list", id) => Feed::List(id.to_string()),
+                ("tag", name) => Feed::Tag(name.to_string()),
+                _ => return None,
+            },
+        })
+    }
+
+    /// API endpoint, with the query string started so limit etc. can be appended
+    fn endpoint(&self) -> String {
+        match self {
+            Feed::Home => "/api/v1/timelines/home?".to_string(),
+            Feed::Local => "/api/v1/timelines/public?local=true&".to_string(),
+            Feed::Federated => "/api/v1/timelines/public?".to_string(),
+            Feed::List(id) => format!("/api/v1/timelines/list/{id}?"),
+            Feed::Tag(name) => format!("/api/v1/timelines/tag/{name}?"),
+            Feed::Bookmarks => "/api/v1/bookmarks?".to_string(),
+            Feed::Favourites => "/api/v1/favourites?".to_string(),
+            Feed::Notifications => "/api/v1/notifications?".to_string(),
+            Feed::Direct => "/api/v1/conversations?".to_string(),
+        }
+    }
+
+    /// Bookmarks and favourites are paged by when they were bookmarked, and conversations by
+    /// their IDs, not by status ID, so since_id doesn't mean anything to them
+    fn supports_since_id(&self) -> bool {
+        !matches!(self, Feed::Bookmarks | Feed::Favourites | Feed::Direct)
+    }
+
+    /// Whether replies need their thread looked up. Notifications aren't posts, and
+    /// conversations come with their whole thread already
+    fn has_threads(&self) -> bool {
+        !matches!(self, Feed::Notifications | Feed::Direct)
+    }
 }
 
-#[derive(Debug)]
-struct Attachment {
-    filename: String,
-    content_type: String,
-    data: Vec<u8>,
+/// The parts of a post (or notification) that end up in an email
+struct Email<'a> {
+    message_id: String,
+    from: &'a Account,
+    subject: String,
+    content: String,
+    media: &'a [MediaAttachment],
+    url: &'a str,
+    created_at: &'a str,
+    in_reply_to: Option<&'a str>,
+    /// Every post above this one in the thread, oldest first
+    references: Vec<&'a str>,
+    /// Direct messages are marked as private
+    private: bool,
+}
+
+/// The emails in a session, only rendered once the client asks for them,
+/// so checking STAT or UIDL doesn't wait on every image in the mailbox
+struct Maildrop<'a> {
+    options: &'a Options,
+    fetcher: &'a Fetcher,
+    account_domain: &'a str,
+    to: (&'a str, &'a str),
+    emails: Vec<Email<'a>>,
+    rendered: Vec<Option<String>>,
+    /// Sizes of emails with images that haven't been rendered yet, going by the images' sizes
+    /// on the server rather than downloading them
+    estimates: Vec<Option<usize>>,
+}
+
+//How long some data gets when it's base64 encoded into an email, in lines of 76
+fn base64_size(len: usize) -> usize {
+    let encoded = len.div_ceil(3) * 4;
+    encoded + encoded.div_ceil(76) * 2
+}
+
+impl<'a> Maildrop<'a> {
+    fn new(
+        options: &'a Options,
+        fetcher: &'a Fetcher,
+        account_domain: &'a str,
+        to: (&'a str, &'a str),
+        emails: Vec<Email<'a>>,
+    ) -> Maildrop<'a> {
+        Maildrop {
+            options,
+            fetcher,
+            account_domain,
+            to,
+            rendered: vec![None; emails.len()],
+            estimates: vec![None; emails.len()],
+            emails,
+        }
+    }
+
+    //Adds an email that doesn't need rendering, after all the others
+    fn add_rendered(&mut self, email: String) {
+        self.rendered.push(Some(email));
+        self.estimates.push(None);
+    }
+
+    //Images that have to be downloaded to render an email
+    fn downloads(&self, i: usize) -> Vec<String> {
+        let Some(email) = self.emails.get(i) else {
+            return vec![];
+        };
+        if !self.options.attachment && !self.options.inline {
+            return vec![];
+        }
+        email
+            .media
+            .iter()
+            .filter_map(|media| media.url.clone())
+            .collect()
+    }
+
+    //The size to give for LIST and STAT, which is exact unless there are images still to download
+    fn size(&mut self, i: usize) -> usize {
+        if let Some(rendered) = &self.rendered[i] {
+            return rendered.len();
+        }
+        //Without images, rendering is cheap enough to just do it
+        if self.downloads(i).is_empty() {
+            return self.message(i).len();
+        }
+        let urls = self.downloads(i);
+        *self.estimates[i].get_or_insert_with(|| {
+            let images: usize = self
+                .fetcher
+                .block_on(self.fetcher.media_sizes(&urls))
+                .into_iter()
+                .flatten()
+                .map(|size| base64_size(size as usize) + ATTACHMENT_HEADERS)
+                .sum();
+            render_email(
+                self.options,
+                &HashMap::new(),
+                &self.emails[i],
+                self.account_domain,
+                self.to,
+            )
+            .len()
+                + images
+        })
+    }
+
+    fn sizes(&mut self) -> Vec<usize> {
+        (0..self.rendered.len()).map(|i| self.size(i)).collect()
+    }
+
+    //Renders an email the first time it's asked for, downloading its images then
+    fn message(&mut self, i: usize) -> &str {
+        let urls = self.downloads(i);
+        self.rendered[i].get_or_insert_with(|| {
+            let downloads = if urls.is_empty() {
+                HashMap::new()
+            } else {
+                self.fetcher.block_on(self.fetcher.attachments(urls))
+            };
+            render_email(
+                self.options,
+                &downloads,
+                &self.emails[i],
+                self.account_domain,
+                self.to,
+            )
+        })
+    }
 }
 
 #[allow(unused)]
@@ -61,25 +258,55 @@ enum POPCommand {
     Dele(u32),
     Noop,
     Rset,
-    Top { msg: u32, n: u32 },
+    Top {
+        msg: u32,
+        n: u32,
+    },
     Uidl(u32),
     User(String),
     Pass(String),
-    Apop,
+    Apop {
+        name: String,
+        digest: String,
+    },
     Disconnect,
     Capa,
-    Auth,
+    /// None if the client gave up, or sent something we couldn't decode, which has already been answered
+    Auth(Option<Cred>),
+    Stls,
 }
 enum SMTPCommand {
-    Mailfrom(String),
+    /// The sender's address isn't kept, as it can't be trusted to say who's posting
+    Mailfrom,
+    /// The mechanism, and maybe the first response, not run until we know AUTH is allowed
+    Auth(String),
     RcptTo(String),
-    Data(String),
+    Data,
     Rset,
     Noop,
     Quit,
     Disconnect,
     Helo,
     Ehlo,
+    StartTls,
+    /// A command we know with arguments we can't make sense of, with what it should have looked like
+    Invalid(&'static str),
+    Unknown,
+}
+
+/// Where an SMTP session is up to, so commands sent out of order can be refused
+enum SMTPState {
+    /// Waiting for HELO or EHLO
+    Connected,
+    /// Ready for MAIL FROM
+    Greeted,
+    /// In the middle of a message
+    Mail {
+        sender: Login,
+        recipients: Vec<String>,
+        /// The accounts among the recipients, as the sender's server names them, ex. "friend@example.com"
+        mentions: Vec<String>,
+    },
 }
 
 //This basically converts the Err to Option, without having to borrow the stream
@@ -90,347 +317,286 @@ macro_rules! send_str {
     };
 }
 
-#[derive(Parser, Debug)]
-#[command(name = "MOP3")]
-#[command(author = "Nathan Kiesman. <nkizz@tacobelllabs.net>")]
-#[command(version = "0.1")]
-#[command(about = "Mastodon to POP3 gateway", long_about = None)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// If set, mastodon account to connect to, ex. user@example.com. Otherwise, uses username from POP3/SMTP connection
-    #[arg(long)]
-    account: Option<String>,
-    /// If set, mastodon token to connect with. Otherwise, uses password from POP3 connection. Required for SMTP server
-    #[arg(long)]
-    token: Option<String>,
-    /// Address to listen on, defaults to localhost
-    #[arg(long)]
-    address: Option<String>,
-    /// POP3 listening port, defaults to 110
-    #[arg(long)]
-    pop3port: Option<u16>,
-    /// SMTP listening port, defaults to 25
-    #[arg(long)]
-    smtpport: Option<u16>,
-    /// Only send ASCII to clients, gracefully converts unicode
-    #[arg(long)]
-    ascii: bool,
-    /// Disables SMTP, posts can only be received, not sent
-    #[arg(long)]
-    nosmtp: bool,
-    /// Enables SMTP, ignored since it is now the default
-    #[arg(long, hide = true)]
-    smtp: bool,
-    /// Enables adding images as binary attachments, don't use with --inline
-    #[arg(long)]
-    attachment: bool,
-    /// Enables inline images, don't use with --attachment
-    #[arg(long)]
-    inline: bool,
-    /// Disables HTML to text conversion, makes links look better if you're using a client that supports HTML
-    #[arg(long)]
-    html: bool,
-    /// Debug mode, currently just prints out the JSON of the timeline
-    #[arg(long)]
-    debug: bool,
-    /// Include the URL of the original post in the email
-    #[arg(long)]
-    url: bool,
-    /// Appends links in posts with a URL proxy. Does not change link text, so links still appears un-proxied. "http://frogfind.com/read.php?a=" works well.
-    #[arg(long)]
-    proxy: Option<String>,
-}
-
 fn main() {
-    let args = Args::parse();
-    if !args.nosmtp {
-        if args.token.is_none() {
-            println!("Error: Must provide token to use SMTP server.");
-            println!("Either supply a token, or use --nosmtp and send it as the POP password.");
-            println!("For more information, run mop3 --help");
+    let mut args = Args::parse();
+    let config_path = args.config_path();
+    let command = args.command.take();
+    let config = match Config::load(args) {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            println!("Error: {}", err);
             return;
         }
-        thread::spawn(smtp_setup);
+    };
+    if let Some(Command::Login { account }) = command {
+        let Some(config_path) = config_path else {
+            println!("Error: Nowhere to save the token, pass --config");
+            return;
+        };
+        if let Err(err) = login::login(&config, &config_path, &account) {
+            println!("Error: {}", err);
+        }
+        return;
     }
-    //Most recent ID fetched, passed to API call to reduce server load
-    let mut recent = "".to_string();
-    let account = (
-        args.address.as_deref().unwrap_or("127.0.0.1"),
-        args.pop3port.unwrap_or(110),
-    );
-
-    let listener = TcpListener::bind(account).unwrap();
-    println!("Listening on {:?}", account);
-
-    loop {
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            println!("Connection from {:?}", stream.peer_addr().unwrap());
-            if let Some(new_recent) = handle_pop_connection(&args, stream, recent.clone()) {
-                recent = new_recent;
-            };
+    //Most recent IDs fetched, per account and client, passed to API call to reduce server load
+    let state = Arc::new(Mutex::new(State::load(&config.state)));
+    //Posts that were still waiting on media when MOP3 last stopped carry on where they were
+    let pending = state.lock().unwrap().pending();
+    for post in pending {
+        println!("Resuming post queued for {}", post.login.address);
+        let state = state.clone();
+        thread::spawn(move || post_later(&post, &state));
+    }
+    if !config.nosmtp {
+        //SMTP needs the state too, to put bounces in the mailbox
+        let smtp = {
+            let (config, state) = (config.clone(), state.clone());
+            move |stream| {
+                handle_smtp_connection(stream, &config, &state);
+            }
+        };
+        if let Some(tls) = &config.tls {
+            let (smtp, addr, tls) = (
+                smtp.clone(),
+                (config.address.clone(), config.smtpsport),
+                tls.clone(),
+            );
+            thread::spawn(move || serve("SMTPS", addr, Some(tls), smtp));
         }
+        let addr = (config.address.clone(), config.smtpport);
+        thread::spawn(move || serve("SMTP", addr, None, smtp));
+    }
+    //Shared by every POP3 session, so there's one async runtime however many clients connect
+    let fetcher = Arc::new(Fetcher::new(config.maxdownloads, media_cache(&config)));
+    fetcher.evict_cache();
+    let pop = {
+        let (config, state, fetcher) = (config.clone(), state.clone(), fetcher.clone());
+        move |stream| {
+            handle_pop_connection(&config, &fetcher, stream, &state);
+        }
+    };
+    if let Some(tls) = &config.tls {
+        let (pop, addr, tls) = (
+            pop.clone(),
+            (config.address.clone(), config.pop3sport),
+            tls.clone(),
+        );
+        thread::spawn(move || serve("POP3S", addr, Some(tls), pop));
     }
+    serve("POP3", (config.address.clone(), config.pop3port), None, pop);
 }
-fn smtp_setup() {
-    let args = Args::parse();
-    let smtp_addr = (
-        args.address.as_deref().unwrap_or("127.0.0.1"),
-        args.smtpport.unwrap_or(25),
-    );
 
-    let smtp_listener = TcpListener::bind(smtp_addr).unwrap();
-    println!("Listening for SMTP on {:?}", smtp_addr);
+//Accepts connections forever, starting TLS straight away for POP3S and SMTPS
+fn serve<F>(name: &str, addr: (String, u16), tls: Option<Arc<ServerConfig>>, handle: F)
+where
+    F: Fn(Connection) + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind((addr.0.as_str(), addr.1)).unwrap();
+    println!("Listening for {} on {:?}", name, addr);
 
     loop {
-        for stream in smtp_listener.incoming() {
-            let stream = stream.unwrap();
-            println!("SMTP Connection from {:?}", stream.peer_addr().unwrap());
-            handle_smtp_connection(stream, &args);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("Could not accept {} connection: {:?}", name, err);
+                    continue;
+                }
+            };
+            println!("{} connection from {:?}", name, stream.peer_addr());
+            let stream = match &tls {
+                Some(tls) => match Connection::tls(stream, tls.clone()) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        println!("Could not start TLS: {:?}", err);
+                        continue;
+                    }
+                },
+                None => Connection::plain(stream),
+            };
+            //Each client gets its own thread, so a slow fetch doesn't hold up everyone else
+            let handle = handle.clone();
+            thread::spawn(move || handle(stream));
         }
     }
 }
 
 fn handle_pop_connection(
-    args: &Args,
-    mut stream: TcpStream,
-    mut recent_id: String,
-) -> Option<String> {
-    stream
-        .write_all("+OK MOP3 ready\r\n".as_bytes())
-        .expect("Couldn't send welcome message");
-
-    let new_cred_res = get_login(&mut stream);
+    config: &Config,
+    fetcher: &Fetcher,
+    mut stream: Connection,
+    state: &Mutex<State>,
+) -> Option<()> {
+    //The timestamp APOP digests are made with, which has to be different every time
+    let timestamp = format!(
+        "<{}.{}@mop3>",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or(0)
+    );
+    send_str!(stream, &format!("+OK MOP3 ready {}\r\n", timestamp))?;
+    //Clients are told apart by address, so each machine gets its own idea of what's new
+    let peer = stream.peer_addr().ok()?.ip().to_string();
+
     //Make sure we didn't drop the connection
-    let mut new_cred = match new_cred_res {
-        Some(cred) => cred,
-        None => return None,
+    let mut new_cred = get_login(config, &mut stream, &timestamp)?;
+    //The feed always comes from the POP3 username, even if the account is from the CLI
+    let (username, feed_tag, feed) = strip_feed(&new_cred.username);
+    new_cred.username = username;
+    let feed = match feed {
+        Some(feed) => feed,
+        None => {
+            send_str!(stream, &format!("-ERR unknown feed {}\r\n", feed_tag))?;
+            return None;
+        }
     };
-    //If credentials have been passed in on the CLI, use them
-    if args.account.as_deref().is_some() {
-        new_cred.username = args.account.as_deref()?.to_string();
-    }
-    if args.token.as_deref().is_some() {
-        new_cred.password = args.token.as_deref()?.to_string();
-    }
-    let (account_domain, account_url) = strip_cred(&new_cred.username);
-
-    let client = Client::new();
+    //The login picks the account, and its token if it's in the config file
+    let Some(login) = config.login(&new_cred.username, &new_cred.password) else {
+        send_str!(stream, "-ERR [AUTH] invalid password\r\n")?;
+        return None;
+    };
+    let Login {
+        domain: account_domain,
+        url: account_url,
+        token,
+        ..
+    } = &login;
 
     //Verify account and get user's display name
-    let account: CredentialAccount = client
-        .get(format!("{account_url}/api/v1/accounts/verify_credentials"))
-        .header("Authorization", "Bearer ".to_owned() + &new_cred.password)
-        .header("User-Agent", USER_AGENT)
-        .send()
-        .expect("Could not verify credentials")
-        .json()
-        .expect("Could not parse credentials, your token is likely invalid, or your are sending a token from one server on another one.");
+    let account: Account = match fetcher.block_on(fetcher.get(
+        &format!("{account_url}/api/v1/accounts/verify_credentials"),
+        token,
+    )) {
+        Ok(account) => account,
+        Err(err) => {
+            println!("Could not verify credentials: {}", err);
+            //A bad token is the likely culprit, or a token from one server being sent to another one
+            send_str!(stream, &pop_error(&err))?;
+            return None;
+        }
+    };
 
     let account_addr = format!("{}@{}", account.username, account_domain);
+    let options = config.options(&login.address);
+    //Each feed is its own mailbox, home keeps the plain address
+    let mailbox_addr = if feed == Feed::Home {
+        account_addr.clone()
+    } else {
+        format!("{}+{}@{}", account.username, feed_tag, account_domain)
+    };
+    let mailbox_key = state::mailbox_key(&mailbox_addr, &peer);
+    //Held until the session ends, however it ends
+    let Some(_lock) = state::lock_mailbox(state, &mailbox_key) else {
+        send_str!(stream, "-ERR [IN-USE] mailbox already in use\r\n")?;
+        return None;
+    };
+    let (mut mailbox, mut threads) = {
+        let state = state.lock().unwrap();
+        (state.mailbox(&mailbox_key), state.threads(&account_addr))
+    };
 
-    //Get timeline
-    let since_id = (!recent_id.is_empty())
-        .then(|| format!("&since_id={recent_id}"))
-        .unwrap_or_default();
-    let timeline_str = client
-        .get(format!(
-            "{account_url}/api/v1/timelines/home?limit=40{since_id}"
-        ))
-        .header("Authorization", "Bearer ".to_owned() + &new_cred.password)
-        .header("User-Agent", USER_AGENT)
-        .send()
-        .expect("Could not retrieve timeline")
-        .text()
-        .unwrap();
-    if args.debug {
-        println!("{}", timeline_str);
-    }
-    //println!("{}", timeline_str);
-    let timeline: Vec<Value> =
-        serde_json::from_str(&timeline_str).expect("Server sent malformed JSON");
-
-    //Total size of all emails, needs to be reported back
-    let mut post_size = 0;
-    let mut emails: Vec<String> = vec![];
-    for post in &timeline {
-        println!("{}", get_str(&post["created_at"]));
-        //If this is a reblog, get text & images from the reblog
-        let (mut content, media_vec, mut subject, url) = if post["reblog"] != Value::Null {
-            (
-                get_str(&post["reblog"]["content"]).to_string(),
-                &post["reblog"]["media_attachments"],
-                string_concat!(
-                    "Boost from ",
-                    get_str(&post["reblog"]["account"]["display_name"])
-                ),
-                get_str(&post["reblog"]["url"]),
-            )
-        } else {
-            (
-                get_str(&post["content"]).to_string(),
-                &post["media_attachments"],
-                "Post".to_string(),
-                get_str(&post["url"]),
-            )
-        };
-        //Replace links with proxy if requested
-        if args.proxy.is_some() {
-            content = content.to_string().replace(
-                "<a href=\"",
-                &string_concat!(" <a href=\"", args.proxy.as_ref().unwrap()).to_owned(),
-            );
+    let timeline = match fetcher.block_on(load_mailbox(
+        config,
+        &options,
+        fetcher,
+        (account_url, token),
+        &feed,
+        &mut mailbox,
+        &mut threads,
+    )) {
+        Ok(timeline) => timeline,
+        Err(err) => {
+            println!("Could not fetch mailbox: {}", err);
+            send_str!(stream, &pop_error(&err))?;
+            return None;
         }
-        //De-HTML-ify content if requested
-        if !args.html {
-            content = from_read(content.as_bytes(), 78).replace('\n', "\r\n");
-        }
-        //Get URLs of any media, and either append them as text, or download images into a Vec
-        let media_urls = media_vec
-            .as_array()
-            .expect("Server sent malformed JSON (no media array)");
-        let mut attachments = Vec::new();
-        if args.attachment || args.inline {
-            for media in media_urls {
-                //Extract info from the JSON response and fetch image
-                let img = client
-                    .get(get_str(&media["url"]))
-                    .header("User-Agent", USER_AGENT)
-                    .send()
-                    .expect("Couldn't get image");
-                let filename = get_str(&media["url"])
-                    .split('/')
-                    .last()
-                    .unwrap()
-                    .to_string();
-                let mime = img
-                    .headers()
-                    .get("Content-Type")
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string();
-                let img_data = img.bytes().unwrap().clone().to_owned();
-                attachments.push(Attachment {
-                    filename,
-                    content_type: mime,
-                    data: img_data.to_vec(),
-                });
-            }
-        } else {
-            for media in media_vec
-                .as_array()
-                .expect("Server sent malformed JSON (no media array)")
-            {
-                content = string_concat!(content, "\r\n", get_str(&media["url"]));
-            }
-        }
-        let mut display_name = get_str(&post["account"]["display_name"]).to_string();
-        if args.ascii {
-            content = deunicode(&content);
-            subject = deunicode(&subject);
-            display_name = deunicode(&display_name);
-        }
-        //If requested, add the URL of the original post to the email
-        if args.url {
-            content = string_concat!(content, "\r\n", url);
-        }
-        //oh lawd he comin
-        let from_address = string_concat!(get_str(&post["account"]["acct"]), "@", account_domain);
-        let mut message = MessageBuilder::new()
-            .from((display_name.as_str(), from_address.as_str()))
-            .to((account.display_name.clone(), account_addr.clone()))
-            .subject(subject)
-            //Fun fact: this line of code is 181 characters long
-            .date(
-                DateTime::<Utc>::from_naive_utc_and_offset(
-                    NaiveDateTime::parse_from_str(
-                        get_str(&post["created_at"]),
-                        "%Y-%m-%dT%H:%M:%S%.3fZ",
-                    )
-                    .expect("Server sent unexpected time format"),
-                    Utc,
-                )
-                .timestamp(),
-            )
-            .message_id(string_concat!(get_str(&post["id"]), "@", account_domain));
-        if args.html {
-            message = message.html_body(content);
-        } else {
-            message = message.text_body(content);
-        }
-        if post["in_reply_to_id"] != Value::Null {
-            message = message.in_reply_to(string_concat!(
-                get_str(&post["in_reply_to_id"]),
-                "@",
-                account_domain
-            ));
-        }
-        if args.attachment {
-            for attachment in attachments {
-                message = message.binary_attachment(
-                    attachment.content_type,
-                    attachment.filename,
-                    attachment.data,
-                );
+    };
+
+    let to = (account.display_name.as_str(), account_addr.as_str());
+    let mut emails: Vec<Email> = vec![];
+    for item in &timeline {
+        emails.push(match (item, &feed) {
+            (Item::Notification(notification), _) => notification_email(notification),
+            (Item::Status(status), Feed::Direct) => {
+                direct_email(status, &timeline, &account.username)
             }
-        } else if args.inline {
-            for attachment in attachments {
-                message = message.binary_inline(
-                    attachment.content_type,
-                    attachment.filename,
-                    attachment.data,
-                );
+            (Item::Status(status), _) => {
+                let mut email = post_email(status);
+                //Posts brought in with --ancestors weren't looked up, but their thread is right here
+                email.references = match threads.get(&status.id) {
+                    Some(thread) => thread.iter().map(String::as_str).collect(),
+                    None => thread_references(status, &timeline),
+                };
+                email
             }
-        }
-        let message = string_concat!(message.write_to_string().unwrap(), "\r\n");
-        post_size += message.len();
-        emails.push(message);
+        });
     }
+    let mut maildrop = Maildrop::new(&options, fetcher, account_domain, to, emails);
+    //Bounces go after the posts, in the home mailbox only, so they're only seen once per client
+    let bounces = if feed == Feed::Home {
+        state.lock().unwrap().bounces(&login.address)
+    } else {
+        vec![]
+    };
+    for bounce in &bounces {
+        maildrop.add_rendered(bounce.email.clone());
+    }
+    let uids: Vec<String> = timeline
+        .iter()
+        .map(|item| item.id().to_string())
+        .chain(bounces.iter().map(|bounce| bounce.id.clone()))
+        .collect();
     send_str!(stream, "+OK MOP3 READY, MESSAGES FETCHED\r\n")?;
+    //Messages marked with DELE, only actually removed if the client QUITs
+    let mut deleted = vec![false; uids.len()];
     //process commands as we get them
     loop {
         //what if we kissed in The TRANSACTION State
-        match get_pop_command(&mut stream) {
+        match get_pop_command(&mut stream, false, false, true) {
             POPCommand::List(index) => {
-                let i = index as usize;
-                if i != 0 {
-                    if i > emails.len() {
-                        send_str!(stream, "-ERR no such message\r\n")?;
-                    } else {
-                        send_str!(stream, &format!("+OK {} {}\r\n", i, emails[i - 1].len()))?;
+                if index != 0 {
+                    match msg_index(index, &deleted) {
+                        Some(i) => {
+                            send_str!(stream, &format!("+OK {} {}\r\n", index, maildrop.size(i)))?
+                        }
+                        None => send_str!(stream, "-ERR no such message\r\n")?,
                     }
                 } else {
+                    let sizes = maildrop.sizes();
+                    let (count, size) = maildrop_size(&sizes, &deleted);
                     send_str!(
                         stream,
-                        &format!("+OK {} messages ({} octets)\r\n", emails.len(), post_size)
+                        &format!("+OK {} messages ({} octets)\r\n", count, size)
                     )?;
-                    for (i, msg) in emails.iter().enumerate() {
-                        send_str!(stream, &format!("{} {}\r\n", i + 1, msg.len()))?;
+                    for (i, size) in sizes.iter().enumerate() {
+                        if !deleted[i] {
+                            send_str!(stream, &format!("{} {}\r\n", i + 1, size))?;
+                        }
                     }
                     send_str!(stream, ".\r\n")?;
                 }
             }
 
-            POPCommand::Retr(i) => {
-                let ind = (i - 1) as usize;
-                if ind >= emails.len() {
-                    send_str!(stream, "-ERR no such message\r\n")?;
-                } else {
-                    send_str!(stream, &format!("+OK {} octets\r\n", emails[ind].len()))?;
-                    send_str!(stream, &emails[ind])?;
+            POPCommand::Retr(index) => match msg_index(index, &deleted) {
+                Some(ind) => {
+                    let email = maildrop.message(ind);
+                    send_str!(stream, &format!("+OK {} octets\r\n", email.len()))?;
+                    send_str!(stream, &dot_stuff(email))?;
                     send_str!(stream, ".\r\n")?;
-                    recent_id = get_str(&timeline[0]["id"]).to_string();
                 }
-            }
+                None => send_str!(stream, "-ERR no such message\r\n")?,
+            },
 
             POPCommand::Stat => {
-                send_str!(stream, &format!("+OK {} {}\r\n", emails.len(), post_size))?
+                let (count, size) = maildrop_size(&maildrop.sizes(), &deleted);
+                send_str!(stream, &format!("+OK {} {}\r\n", count, size))?
             }
             POPCommand::Uidl(ind) => {
                 let i = ind as usize;
                 if i != 0 {
-                    if i > emails.len() {
+                    if msg_index(ind, &deleted).is_none() {
                         send_str!(stream, "-ERR no such message\r\n")?;
                     } else {
                         send_str!(
@@ -439,7 +605,7 @@ fn handle_pop_connection(
                                 "+OK ",
                                 ind.to_string(),
                                 " ",
-                                get_str(&timeline[i - 1]["id"]),
+                                uids[i - 1],
                                 "@",
                                 account_domain,
                                 "\r\n"
@@ -448,23 +614,22 @@ fn handle_pop_connection(
                     }
                 } else {
                     send_str!(stream, "+OK\r\n")?;
-                    for (i, msg) in timeline.iter().enumerate() {
-                        send_str!(
-                            stream,
-                            &format!("{} {}@{}\r\n", i + 1, get_str(&msg["id"]), account_domain)
-                        )?;
+                    for (i, uid) in uids.iter().enumerate() {
+                        if !deleted[i] {
+                            send_str!(
+                                stream,
+                                &format!("{} {}@{}\r\n", i + 1, uid, account_domain)
+                            )?;
+                        }
                     }
                     send_str!(stream, ".\r\n")?;
                 }
             }
-            POPCommand::Top { msg, mut n } => {
+            POPCommand::Top { msg, mut n } => match msg_index(msg, &deleted) {
                 //This is basically RETR
-                let ind = (msg - 1) as usize;
-                if ind >= emails.len() {
-                    send_str!(stream, "-ERR no such message\r\n")?;
-                } else {
+                Some(ind) => {
                     let mut partial = "".to_string();
-                    let lines = emails[ind].lines();
+                    let lines = maildrop.message(ind).lines();
                     let mut msg_flag = false;
                     for line in lines {
                         //This is a terribly inefficent way to do this
@@ -475,170 +640,1245 @@ fn handle_pop_connection(
                             }
                             n -= 1;
                         }
-                        if line == "" {
+                        if line.is_empty() {
                             msg_flag = true;
                         }
                     }
                     send_str!(stream, &format!("+OK {} octets\r\n", partial.len()))?;
-                    send_str!(stream, &partial)?;
+                    send_str!(stream, &dot_stuff(&partial))?;
                     send_str!(stream, ".\r\n")?;
                 }
+                None => send_str!(stream, "-ERR no such message\r\n")?,
+            },
+            POPCommand::Dele(index) => match msg_index(index, &deleted) {
+                Some(ind) => {
+                    deleted[ind] = true;
+                    send_str!(stream, &format!("+OK message {} deleted\r\n", index))?;
+                }
+                None => send_str!(stream, "-ERR no such message\r\n")?,
+            },
+            POPCommand::Rset => {
+                deleted.fill(false);
+                let (count, size) = maildrop_size(&maildrop.sizes(), &deleted);
+                send_str!(
+                    stream,
+                    &format!("+OK maildrop has {} messages ({} octets)\r\n", count, size)
+                )?;
+            }
+            //The UPDATE state, where deletions actually happen
+            POPCommand::Quit => {
+                for (i, post) in timeline.iter().enumerate() {
+                    if deleted[i] {
+                        mailbox.deleted.push(post.id().to_string());
+                        //Deleting a notification email dismisses it on Mastodon too
+                        if feed == Feed::Notifications {
+                            fetcher.block_on(fetcher.dismiss_notification(
+                                account_url,
+                                token,
+                                post.id(),
+                            ));
+                        }
+                    }
+                }
+                let deleted_bounces: Vec<&str> = bounces
+                    .iter()
+                    .zip(&deleted[timeline.len()..])
+                    .filter(|(_, deleted)| **deleted)
+                    .map(|(bounce, _)| bounce.id.as_str())
+                    .collect();
+                mailbox.window = timeline
+                    .into_iter()
+                    .zip(&deleted)
+                    .filter(|(_, deleted)| !**deleted)
+                    .map(|(post, _)| post)
+                    .collect();
+                mailbox.forget_old_deletions();
+                let remaining = deleted.iter().filter(|d| !**d).count();
+                let mut state = state.lock().unwrap();
+                state.set_mailbox(&mailbox_key, mailbox);
+                state.add_threads(&account_addr, threads.clone());
+                state.remove_bounces(&login.address, &deleted_bounces);
+                if let Err(err) = state.save() {
+                    println!("Could not save state: {:?}", err);
+                    send_str!(stream, "-ERR some deleted messages not removed\r\n")?;
+                    return Some(());
+                }
+                send_str!(
+                    stream,
+                    &format!("+OK MOP3 signing off ({} messages left)\r\n", remaining)
+                )?;
+                return Some(());
+            }
+            //If the client just hangs up, nothing gets deleted, but we still keep what it was sent
+            POPCommand::Disconnect => {
+                let mut state = state.lock().unwrap();
+                state.set_mailbox(&mailbox_key, mailbox);
+                state.add_threads(&account_addr, threads.clone());
+                if let Err(err) = state.save() {
+                    println!("Could not save state: {:?}", err);
+                }
+                return Some(());
+            }
+            POPCommand::Apop { .. } | POPCommand::Stls => {
+                send_str!(stream, "-ERR already logged in\r\n")?;
             }
-            POPCommand::Quit | POPCommand::Disconnect => return Some(recent_id),
             _ => (),
         }
     }
 }
 
-fn handle_smtp_connection(mut stream: TcpStream, args: &Args) {
-    stream
-        .write_all("220 hi welcome to chilis\r\n".as_bytes())
-        .expect("Couldn't send welcome message");
-    let mut from = "".to_string();
+//Opens the image cache, unless it's turned off or there's nowhere to put it
+fn media_cache(config: &Config) -> Option<MediaCache> {
+    if config.cachesize == 0 || !config.images() {
+        return None;
+    }
+    let dir = &config.cachedir;
+    let max_age = Duration::from_secs(config.cacheexpiry * 24 * 60 * 60);
+    match MediaCache::new(dir, config.cachesize * 1024 * 1024, max_age) {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            println!(
+                "Could not open media cache {:?}, not caching images: {}",
+                dir, err
+            );
+            None
+        }
+    }
+}
+
+//Doubles the dot at the start of lines, so a line that's just a dot can't end the message early.
+//Bounces carry whatever the client sent, which can have them (RFC 1939 section 3)
+fn dot_stuff(email: &str) -> String {
+    let stuffed = email.replace("\n.", "\n..");
+    match stuffed.strip_prefix('.') {
+        Some(_) => string_concat!(".", stuffed),
+        None => stuffed,
+    }
+}
+
+//Converts a one indexed message number to an index into the mailbox, if that message exists and isn't deleted
+fn msg_index(msg: u32, deleted: &[bool]) -> Option<usize> {
+    let ind = (msg as usize).checked_sub(1)?;
+    (ind < deleted.len() && !deleted[ind]).then_some(ind)
+}
+
+//Number of messages and total size of the mailbox, not counting deleted messages
+fn maildrop_size(sizes: &[usize], deleted: &[bool]) -> (usize, usize) {
+    sizes
+        .iter()
+        .zip(deleted)
+        .filter(|(_, deleted)| !**deleted)
+        .fold((0, 0), |(count, total), (size, _)| {
+            (count + 1, total + size)
+        })
+}
+
+//Fetches what's new in the feed and adds it to the mailbox, returning everything that's in it now
+//threads is the account's known threads, which any new ones are added to
+async fn load_mailbox(
+    config: &Config,
+    options: &Options,
+    fetcher: &Fetcher,
+    (account_url, token): (&str, &str),
+    feed: &Feed,
+    mailbox: &mut MailboxState,
+    threads: &mut HashMap<String, Vec<String>>,
+) -> Result<Vec<Item>, ApiError> {
+    //Get timeline
+    let since_id = if feed.supports_since_id() {
+        mailbox.recent_id.as_str()
+    } else {
+        ""
+    };
+    let url = format!("{account_url}{}limit=40", feed.endpoint());
+    let max_fetch = options.maxfetch;
+    let mut timeline: Vec<Item> = match feed {
+        Feed::Notifications => fetcher
+            .timeline(&url, token, since_id, max_fetch, config.debug)
+            .await?
+            .into_iter()
+            .map(Item::Notification)
+            .collect(),
+        //Conversations only have their latest message, so fill in the rest
+        Feed::Direct => {
+            let conversations = fetcher
+                .timeline(&url, token, since_id, max_fetch, config.debug)
+                .await?;
+            expand_conversations(fetcher, account_url, token, conversations, mailbox)
+                .await
+                .into_iter()
+                .map(Item::Status)
+                .collect()
+        }
+        _ => fetcher
+            .timeline(&url, token, since_id, max_fetch, config.debug)
+            .await?
+            .into_iter()
+            .map(Item::Status)
+            .collect(),
+    };
+    if let Some(newest) = timeline.first() {
+        mailbox.recent_id = newest.id().to_string();
+    }
+    //Look up the rest of the thread above any replies that haven't been yet, so clients can
+    //thread them, and if requested, bring in the posts they're replying to.
+    //Only so many per session, as it's one request each, and the client is waiting on PASS.
+    //The rest are tried again next time, newest first
+    if feed.has_threads() {
+        let mut seen = HashSet::new();
+        let replies: Vec<&str> = timeline
+            .iter()
+            .chain(&mailbox.window)
+            .filter_map(Item::status)
+            .filter(|post| post.in_reply_to_id.is_some() && !threads.contains_key(&post.id))
+            .map(|post| post.id.as_str())
+            .filter(|id| seen.insert(*id))
+            .take(THREAD_LOOKUPS)
+            .collect();
+        let contexts = fetcher.contexts(account_url, token, &replies).await;
+        let mut missing = vec![];
+        for (id, context) in replies.into_iter().zip(contexts) {
+            //References are only cosmetic, so a failed lookup (ex. rate limiting on a busy feed)
+            //falls back to what's in the mailbox, and is tried again next time
+            let context = match context {
+                Ok(context) => context,
+                Err(err) => {
+                    println!("Could not fetch thread for {}: {}", id, err);
+                    continue;
+                }
+            };
+            threads.insert(
+                id.to_string(),
+                context
+                    .ancestors
+                    .iter()
+                    .map(|ancestor| ancestor.id.clone())
+                    .collect(),
+            );
+            if options.ancestors {
+                missing.extend(context.ancestors.into_iter().map(Item::Status));
+            }
+        }
+        timeline.append(&mut missing);
+    }
+    Ok(merge_window(timeline, mailbox, options.window))
+}
+
+//New posts go on top of the ones still in the mailbox from last time,
+//and posts the client has already deleted shouldn't come back.
+//Everything new is kept, even if there's more than fits in the window
+fn merge_window(mut timeline: Vec<Item>, mailbox: &mut MailboxState, window: usize) -> Vec<Item> {
+    let window = window.max(timeline.len());
+    timeline.append(&mut mailbox.window);
+    let mut seen = HashSet::new();
+    timeline.retain(|post| {
+        seen.insert(post.id().to_string()) && !mailbox.deleted.iter().any(|id| id == post.id())
+    });
+    timeline.truncate(window);
+    mailbox.window = timeline.clone();
+    timeline
+}
+
+//Tells the client why we couldn't get its mail, with RFC 2449/3206 response codes
+fn pop_error(err: &ApiError) -> String {
+    let code = match err {
+        ApiError::Auth(_) => "AUTH",
+        ApiError::Temp(_) => "SYS/TEMP",
+        ApiError::Perm(_) => "SYS/PERM",
+    };
+    format!("-ERR [{}] {}\r\n", code, err)
+}
+
+//Pulls the parts of a timeline post that end up in the email out of the status
+fn post_email(post: &Status) -> Email<'_> {
+    //If this is a reblog, get text & images from the reblog
+    let (content, shown, subject) = match &post.reblog {
+        Some(reblog) => (
+            reblog.content.clone(),
+            reblog.as_ref(),
+            string_concat!("Boost from ", reblog.account.display_name),
+        ),
+        None => (post.content.clone(), post, "Post".to_string()),
+    };
+    Email {
+        message_id: post.id.clone(),
+        from: &post.account,
+        subject,
+        content: string_concat!(content, poll_html(shown), card_html(shown)),
+        media: &shown.media_attachments,
+        url: shown.url.as_deref().unwrap_or_default(),
+        created_at: &post.created_at,
+        in_reply_to: post.in_reply_to_id.as_deref(),
+        references: vec![],
+        private: false,
+    }
+}
+
+//Polls can't be voted on from email, but the options and results can at least be shown
+fn poll_html(status: &Status) -> String {
+    let Some(poll) = &status.poll else {
+        return String::new();
+    };
+    let options: String = poll
+        .options
+        .iter()
+        .map(|option| match option.votes_count {
+            Some(votes) => format!("<li>{} ({} votes)</li>", option.title, votes),
+            None => format!("<li>{}</li>", option.title),
+        })
+        .collect();
+    let state = if poll.expired { "Closed" } else { "Open" };
+    format!(
+        "<p>{} poll, {} votes:</p><ul>{}</ul>",
+        state, poll.votes_count, options
+    )
+}
+
+//The link preview, as a link
+fn card_html(status: &Status) -> String {
+    match &status.card {
+        Some(card) => format!(
+            "<p><a href=\"{}\">{}</a><br>{}</p>",
+            card.url, card.title, card.description
+        ),
+        None => String::new(),
+    }
+}
+
+//Same as post_email, but for notifications, which may or may not have a post attached
+fn notification_email(notification: &Notification) -> Email<'_> {
+    let account = &notification.account;
+    let name = &account.display_name;
+    let (subject, action) = match notification.kind.as_str() {
+        "mention" => (string_concat!("Mention from ", name), "mentioned you"),
+        "status" => (string_concat!("New post from ", name), "posted"),
+        "reblog" => (
+            string_concat!(name, " boosted your post"),
+            "boosted your post",
+        ),
+        "favourite" => (
+            string_concat!(name, " favourited your post"),
+            "favourited your post",
+        ),
+        "follow" => (string_concat!(name, " followed you"), "followed you"),
+        "follow_request" => (
+            string_concat!(name, " requested to follow you"),
+            "requested to follow you",
+        ),
+        "poll" => (
+            "A poll you voted in or created has ended".to_string(),
+            "had a poll end",
+        ),
+        "update" => (string_concat!(name, " edited a post"), "edited a post"),
+        _ => (string_concat!("Notification from ", name), "did something"),
+    };
+    let Some(status) = &notification.status else {
+        //Nothing to quote, so say what happened and who they are
+        return Email {
+            message_id: string_concat!("notification.", notification.id),
+            from: account,
+            subject,
+            content: format!(
+                "<p>{} (@{}) {}</p>{}",
+                name, account.acct, action, account.note
+            ),
+            media: &[],
+            url: &account.url,
+            created_at: &notification.created_at,
+            in_reply_to: None,
+            references: vec![],
+            private: false,
+        };
+    };
+    let mut email = post_email(status);
+    email.subject = subject;
+    email.from = account;
+    email.created_at = &notification.created_at;
+    //Mentions and new posts are the post itself, everything else is about one of the user's posts,
+    //so thread it under that post
+    if !matches!(notification.kind.as_str(), "mention" | "status") {
+        email.message_id = string_concat!("notification.", notification.id);
+        email.in_reply_to = Some(&status.id);
+    }
+    email
+}
+
+//Same as post_email, but for one message of a direct conversation. The subject is the same
+//for the whole conversation, and References has the rest of it so clients thread it properly
+fn direct_email<'a>(status: &'a Status, conversation: &'a [Item], me: &str) -> Email<'a> {
+    let mut email = post_email(status);
+    email.subject = if !status.spoiler_text.is_empty() {
+        status.spoiler_text.clone()
+    } else {
+        //Everyone in the conversation is mentioned, apart from whoever is talking
+        let mut people: Vec<&str> = status
+            .mentions
+            .iter()
+            .map(|mention| mention.acct.as_str())
+            .chain([status.account.acct.as_str()])
+            .filter(|acct| *acct != me)
+            .collect();
+        people.sort_unstable();
+        people.dedup();
+        let people: Vec<String> = people
+            .iter()
+            .map(|acct| string_concat!("@", acct))
+            .collect();
+        string_concat!("Conversation with ", people.join(", "))
+    };
+    email.references = thread_references(status, conversation);
+    email.private = true;
+    email
+}
+
+//Walks up in_reply_to_id through the posts we have, returning the IDs oldest first
+fn thread_references<'a>(status: &Status, posts: &'a [Item]) -> Vec<&'a str> {
+    let mut references = vec![];
+    let mut parent = status.in_reply_to_id.as_deref();
+    while let Some(id) = parent {
+        match posts
+            .iter()
+            .filter_map(Item::status)
+            .find(|post| post.id == id)
+        {
+            //Guard against loops, just in case
+            Some(post) if !references.contains(&post.id.as_str()) => {
+                references.push(post.id.as_str());
+                parent = post.in_reply_to_id.as_deref();
+            }
+            _ => break,
+        }
+    }
+    references.reverse();
+    references
+}
+
+//Turns conversations into every direct message in them, newest first.
+//Conversations whose latest message we already have are skipped, as nothing new has been said
+async fn expand_conversations(
+    fetcher: &Fetcher,
+    account_url: &str,
+    token: &str,
+    conversations: Vec<Conversation>,
+    mailbox: &MailboxState,
+) -> Vec<Status> {
+    //Conversations with nothing new since the client last saw them, or deleted them, are skipped
+    let latest: Vec<Status> = conversations
+        .into_iter()
+        .filter_map(|conversation| conversation.last_status)
+        .filter(|last_status| {
+            !mailbox
+                .window
+                .iter()
+                .any(|post| post.id() == last_status.id)
+                && !mailbox.deleted.contains(&last_status.id)
+        })
+        .collect();
+    let ids: Vec<&str> = latest.iter().map(|status| status.id.as_str()).collect();
+    let contexts = fetcher.contexts(account_url, token, &ids).await;
+    let mut statuses = vec![];
+    for (last_status, context) in latest.iter().zip(contexts) {
+        //Without the rest of the conversation, the latest message still gets through
+        let context = match context {
+            Ok(context) => context,
+            Err(err) => {
+                println!("Could not fetch conversation {}: {}", last_status.id, err);
+                statuses.push(last_status.clone());
+                continue;
+            }
+        };
+        statuses.extend(
+            context
+                .ancestors
+                .into_iter()
+                .chain(context.descendants)
+                .filter(|post| post.visibility == "direct"),
+        );
+        statuses.push(last_status.clone());
+    }
+    //Timestamps sort nicely as strings
+    statuses.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    statuses
+}
+
+//oh lawd he comin
+fn render_email(
+    options: &Options,
+    downloads: &HashMap<String, Result<Attachment, ApiError>>,
+    email: &Email,
+    account_domain: &str,
+    to: (&str, &str),
+) -> String {
+    println!("{}", email.created_at);
+    let Email {
+        message_id,
+        from,
+        subject,
+        content,
+        media,
+        url,
+        created_at,
+        in_reply_to,
+        references,
+        private,
+    } = email;
+    let mut subject = subject.clone();
+    let mut content = content.clone();
+    //Replace links with proxy if requested
+    if let Some(proxy) = &options.proxy {
+        content = content.to_string().replace(
+            "<a href=\"",
+            &string_concat!(" <a href=\"", proxy).to_owned(),
+        );
+    }
+    //De-HTML-ify content if requested
+    if !options.html {
+        content = from_read(content.as_bytes(), 78).replace('\n', "\r\n");
+    }
+    //Get URLs of any media, and either append them as text, or attach the downloaded images
+    let media_urls = media.iter().filter_map(|media| media.url.as_deref());
+    let mut attachments = Vec::new();
+    if options.attachment || options.inline {
+        for media_url in media_urls {
+            //If the image couldn't be fetched, a link is better than nothing
+            match downloads.get(media_url) {
+                Some(Ok(attachment)) => attachments.push(attachment),
+                Some(Err(err)) => {
+                    println!("Couldn't get image {}: {}", media_url, err);
+                    content = string_concat!(content, "\r\n", media_url);
+                }
+                None => content = string_concat!(content, "\r\n", media_url),
+            }
+        }
+    } else {
+        for media_url in media_urls {
+            content = string_concat!(content, "\r\n", media_url);
+        }
+    }
+    let mut display_name = from.display_name.clone();
+    if options.ascii {
+        content = deunicode(&content);
+        subject = deunicode(&subject);
+        display_name = deunicode(&display_name);
+    }
+    //If requested, add the URL of the original post to the email
+    if options.url {
+        content = string_concat!(content, "\r\n", url);
+    }
+    let from_address = string_concat!(from.acct, "@", account_domain);
+    let mut message = MessageBuilder::new()
+        .from((display_name.as_str(), from_address.as_str()))
+        .to(to)
+        .subject(subject)
+        //Fun fact: this line of code is 181 characters long
+        .date(
+            NaiveDateTime::parse_from_str(created_at, "%Y-%m-%dT%H:%M:%S%.3fZ")
+                .map(|date| DateTime::<Utc>::from_naive_utc_and_offset(date, Utc).timestamp())
+                .unwrap_or_else(|err| {
+                    println!("Server sent unexpected time format {}: {}", created_at, err);
+                    Utc::now().timestamp()
+                }),
+        )
+        .message_id(string_concat!(message_id, "@", account_domain));
+    if options.html {
+        message = message.html_body(content);
+    } else {
+        message = message.text_body(content);
+    }
+    if let Some(in_reply_to) = in_reply_to {
+        message = message.in_reply_to(string_concat!(in_reply_to, "@", account_domain));
+    }
+    if !references.is_empty() {
+        message = message.references(
+            references
+                .iter()
+                .map(|id| string_concat!(id, "@", account_domain))
+                .collect::<Vec<String>>(),
+        );
+    }
+    if *private {
+        message = message.header("Sensitivity", headers::raw::Raw::new("Private"));
+    }
+    if options.attachment {
+        for attachment in attachments {
+            message = message.binary_attachment(
+                attachment.content_type.as_str(),
+                attachment.filename.as_str(),
+                attachment.data.as_slice(),
+            );
+        }
+    } else if options.inline {
+        for attachment in attachments {
+            message = message.binary_inline(
+                attachment.content_type.as_str(),
+                attachment.filename.as_str(),
+                attachment.data.as_slice(),
+            );
+        }
+    }
+    string_concat!(message.write_to_string().unwrap(), "\r\n")
+}
+
+fn handle_smtp_connection(
+    mut stream: Connection,
+    config: &Config,
+    state: &Arc<Mutex<State>>,
+) -> Option<()> {
+    send_str!(stream, "220 hi welcome to chilis\r\n")?;
+    //Who the client logged in as with AUTH
+    let mut login: Option<Login> = None;
+    let mut session = SMTPState::Connected;
     loop {
-        match get_smtp_command(stream.try_clone().unwrap()) {
-            SMTPCommand::Mailfrom(addr) => {
-                from = addr;
-            }
-            SMTPCommand::Data(email_string) => {
-                println!("{}", from);
-                let (_, account_url) = if args.account.as_deref().is_some() {
-                    strip_cred(args.account.as_deref().unwrap())
-                } else {
-                    strip_cred(&from)
+        let starttls = config.tls.is_some() && !stream.is_tls();
+        //Passwords wait for TLS if it's there to be had (RFC 3207 section 4.3)
+        let auth = !starttls || config.plaintextauth;
+        match get_smtp_command(&mut stream) {
+            //Saying hello again starts over, like RSET
+            SMTPCommand::Helo => {
+                send_str!(stream, "250 mop3 whats poppin\r\n")?;
+                session = SMTPState::Greeted;
+            }
+            SMTPCommand::Ehlo => {
+                send_str!(
+                    stream,
+                    &format!(
+                        "250-mop3 whats poppin\r\n250-SIZE 5000000\r\n{}{}250 OK\r\n",
+                        if starttls { "250-STARTTLS\r\n" } else { "" },
+                        if auth { "250-AUTH PLAIN LOGIN\r\n" } else { "" }
+                    )
+                )?;
+                session = SMTPState::Greeted;
+            }
+            SMTPCommand::Auth(args) => {
+                if !auth {
+                    send_str!(stream, "530 5.7.0 Must issue STARTTLS first\r\n")?;
+                    continue;
+                }
+                if login.is_some() {
+                    send_str!(stream, "503 5.5.1 Already authenticated\r\n")?;
+                    continue;
+                }
+                if !matches!(session, SMTPState::Greeted) {
+                    send_str!(
+                        stream,
+                        "503 5.5.1 AUTH only allowed after EHLO and outside a message\r\n"
+                    )?;
+                    continue;
+                }
+                //Anything wrong with the exchange itself has already been answered
+                let Some(cred) = smtp_auth(&mut stream, &args) else {
+                    continue;
                 };
-                let auth = string_concat!("Bearer ", args.token.as_deref().unwrap().to_string());
-                let msg = Message::parse(email_string.as_bytes()).expect("Error in parsing email");
-                let in_reply_to = msg.in_reply_to();
-                let references = msg.references();
-                let mut status = msg.body_text(0).unwrap().to_string();
-                //We set the msg-id to the ID of the mastodon post, and this will
-                //be referenced in either the in-reply-to or references header
-                let mut reply_id = if in_reply_to != &HeaderValue::Empty {
-                    in_reply_to.as_text_list().unwrap()[0]
-                } else if references != &HeaderValue::Empty {
-                    references.as_text_list().unwrap()[0]
-                } else {
-                    ""
+                let reply = match smtp_login(config, &cred) {
+                    Ok(new_login) => {
+                        login = Some(new_login);
+                        "235 2.7.0 Authentication successful\r\n".to_string()
+                    }
+                    Err(err) => {
+                        println!("SMTP login failed for {}: {}", cred.username, err);
+                        match err {
+                            ApiError::Temp(msg) => format!("454 4.7.0 {}\r\n", msg),
+                            ApiError::Auth(_) | ApiError::Perm(_) => {
+                                "535 5.7.8 Authentication credentials invalid\r\n".to_string()
+                            }
+                        }
+                    }
                 };
-
-                //Stolen from https://github.com/crisp-oss/email-reply-parser/lib/regex.js
-                //only supports English and certain email clients
-                //which I know is not good to use English as a default, but for a project of this scope,I think it's ok.
-                //If it's including the original message, disable including original replies in your email client
-                let reply_pattern = Regex::new(r"\s*>* On .* wrote:").unwrap();
-                if !reply_id.is_empty() {
-                    //if let Ok(ind) = reply_pattern.find(&status) {
-                    //    status = status.split_at(ind.0).0.to_string()
-                    //}
-                    let result = reply_pattern.find(&status).unwrap();
-                    if result.is_some() {
-                        println!("{:?}", result);
-                        let ind = result.unwrap();
-                        status = status.split_at(ind.start()).0.to_string();
+                send_str!(stream, &reply)?;
+            }
+            SMTPCommand::Mailfrom => match session {
+                SMTPState::Connected => send_str!(stream, "503 5.5.1 Send HELO or EHLO first\r\n")?,
+                SMTPState::Mail { .. } => send_str!(stream, "503 5.5.1 Already got MAIL FROM\r\n")?,
+                //Without AUTH, only --account can be posted as, whatever MAIL FROM says
+                SMTPState::Greeted => match login.clone().or_else(|| config.default_login()) {
+                    Some(sender) => {
+                        send_str!(stream, "250 OK\r\n")?;
+                        session = SMTPState::Mail {
+                            sender,
+                            recipients: vec![],
+                            mentions: vec![],
+                        };
                     }
-                    println!("status: {:?}", status);
-                    break;
+                    None => send_str!(stream, "530 5.7.0 Authentication required\r\n")?,
+                },
+            },
+            SMTPCommand::RcptTo(addr) => match &mut session {
+                SMTPState::Mail {
+                    sender,
+                    recipients,
+                    mentions,
+                } => {
+                    //Anyone not at VISIBILITY_DOMAIN has to be someone Mastodon can find
+                    let reply = match addr.rsplit_once('@') {
+                        Some((_, domain)) if domain.eq_ignore_ascii_case(VISIBILITY_DOMAIN) => {
+                            recipients.push(addr);
+                            "250 OK\r\n".to_string()
+                        }
+                        _ => match resolve_account(sender, &addr) {
+                            Ok(Some(account)) => {
+                                //Sending to someone twice, or Reply-All including ourselves,
+                                //shouldn't add a mention
+                                if !is_sender(sender, &account.acct)
+                                    && !mentions
+                                        .iter()
+                                        .any(|acct| acct.eq_ignore_ascii_case(&account.acct))
+                                {
+                                    mentions.push(account.acct);
+                                }
+                                recipients.push(addr);
+                                "250 OK\r\n".to_string()
+                            }
+                            Ok(None) => format!("550 5.1.1 No fediverse account {}\r\n", addr),
+                            Err(err) => {
+                                println!("Could not look up {}: {}", addr, err);
+                                smtp_error(&err)
+                            }
+                        },
+                    };
+                    send_str!(stream, &reply)?;
                 }
-                //Strip whitespace and inline image markers from the end of status
-                status = status.replace('\u{FFFC}', "");
-                status = status.trim_end().to_string();
-                //Some clients will add the domain to IDs, so strip that
-                if reply_id.contains('@') {
-                    reply_id = reply_id.rsplit_once('@').unwrap().0;
+                _ => send_str!(stream, "503 5.5.1 Send MAIL FROM first\r\n")?,
+            },
+            SMTPCommand::Data => {
+                let SMTPState::Mail {
+                    sender,
+                    recipients,
+                    mentions,
+                } = &session
+                else {
+                    send_str!(stream, "503 5.5.1 Send MAIL FROM first\r\n")?;
+                    continue;
+                };
+                if recipients.is_empty() {
+                    send_str!(stream, "503 5.5.1 Send RCPT TO first\r\n")?;
+                    continue;
                 }
-                //Make an empty string vector
-                let mut media_ids = Vec::new();
-                let client = Client::new();
-                for attachment in msg.attachments() {
-                    if !attachment.is_message() {
-                        //Get the attachment info out of the email
-                        let bigtype = attachment
-                            .content_type()
-                            .expect("Error parsing attachment content type")
-                            .ctype();
-                        let subtype = attachment
-                            .content_type()
-                            .expect("Error parsing attachment content type")
-                            .subtype()
-                            .unwrap_or("JPG");
-                        let mime = string_concat!(bigtype, "/", subtype);
-                        let name = attachment
-                            .attachment_name()
-                            .unwrap_or("Untitled.jpg")
-                            .to_owned();
-                        println!("Attachment Name: {:?}", name);
-                        println!("Attachment Type: {:?}", mime);
-                        //std::fs::write(attachment.attachment_name().unwrap_or("Untitled"), attachment.contents());
-                        let content = attachment.contents().to_owned();
-
-                        //Upload the image, we are given an ID in the reply which needs to be included in the post
-                        let file_part = Part::bytes(content)
-                            .file_name(name)
-                            .mime_str(&mime)
-                            .unwrap();
-                        let form = Form::new().part("file", file_part);
-                        let upload_res = client
-                            .post(account_url.clone() + "/api/v2/media")
-                            .header("Authorization", auth.clone())
-                            .header("User-Agent", USER_AGENT)
-                            .multipart(form)
-                            .send()
-                            .expect("Error uploading image")
-                            .text()
-                            .unwrap();
-                        let ret_vec: Value =
-                            serde_json::from_str(&upload_res).expect("Image upload failure");
-                        let cur_id = get_str(&ret_vec["id"]).to_owned();
-                        media_ids.push(cur_id);
-                    }
+                send_str!(stream, "354 Send message content\r\n")?;
+                let email_string = get_smtp_data(&mut stream)?;
+                println!("{}", sender.address);
+                //Only answer once the post is up, so the client knows if it failed
+                let reply = match Message::parse(email_string.as_bytes()) {
+                    Some(msg) => match draft_post(sender, &msg, recipients, mentions) {
+                        Ok((form, processing)) if processing.is_empty() => {
+                            match post_status(sender, &form) {
+                                Ok(()) => "250 OK\r\n".to_string(),
+                                Err(err) => {
+                                    println!("Could not post: {}", err);
+                                    smtp_error(&err)
+                                }
+                            }
+                        }
+                        //Mastodon won't take the post until its media is ready, which can be minutes
+                        Ok((form, processing)) => {
+                            let post = PendingPost {
+                                id: unique_id("pending"),
+                                login: sender.clone(),
+                                form,
+                                processing,
+                                email: email_string.clone(),
+                                recipients: recipients.clone(),
+                            };
+                            queue_post(post, state);
+                            "250 OK queued until media finishes processing\r\n".to_string()
+                        }
+                        Err(err) => {
+                            println!("Could not post: {}", err);
+                            smtp_error(&err)
+                        }
+                    },
+                    None => "554 5.6.0 Could not parse message\r\n".to_string(),
+                };
+                send_str!(stream, &reply)?;
+                session = SMTPState::Greeted;
+            }
+            SMTPCommand::Rset => {
+                if !matches!(session, SMTPState::Connected) {
+                    session = SMTPState::Greeted;
                 }
-                //We can only have 4 images per post
-                //Hint: if you want to DDOS a mastodon instance, look here :)
-                if media_ids.len() > 4 {
-                    media_ids = media_ids[0..4].to_vec();
+                send_str!(stream, "250 OK\r\n")?;
+            }
+            SMTPCommand::Noop => send_str!(stream, "250 OK\r\n")?,
+            SMTPCommand::StartTls => match &config.tls {
+                Some(tls) if starttls => {
+                    send_str!(stream, "220 2.0.0 Ready to start TLS\r\n")?;
+                    stream.start_tls(tls.clone()).ok()?;
+                    //Nothing from before TLS can be trusted, so the client has to say EHLO again (RFC 3207 section 4.2)
+                    login = None;
+                    session = SMTPState::Connected;
                 }
-                //Wrap the reply and convert to String
-                let in_reply_to_id = if reply_id.is_empty() {
-                    None
-                } else {
-                    Some(reply_id.to_string())
+                _ => send_str!(stream, "454 4.7.0 TLS not available\r\n")?,
+            },
+            SMTPCommand::Invalid(syntax) => {
+                send_str!(stream, &format!("501 5.5.4 Syntax: {}\r\n", syntax))?
+            }
+            SMTPCommand::Unknown => send_str!(stream, "500 5.5.2 Command not recognized\r\n")?,
+            SMTPCommand::Quit => {
+                send_str!(stream, "221 good bye\r\n")?;
+                return Some(());
+            }
+            SMTPCommand::Disconnect => return Some(()),
+        }
+    }
+}
+
+//Checks an SMTP login, making sure the token works before anything is sent with it
+fn smtp_login(config: &Config, cred: &Cred) -> Result<Login, ApiError> {
+    //A feed tag doesn't matter here, but the same username as POP3 should work
+    let (address, _, _) = strip_feed(&cred.username);
+    let login = config
+        .login(&address, &cred.password)
+        .ok_or_else(|| ApiError::Auth("Invalid password".to_string()))?;
+    let _: Account = mastodon::parse(
+        reqwest::blocking::Client::new()
+            .get(format!("{}/api/v1/accounts/verify_credentials", login.url))
+            .header("Authorization", string_concat!("Bearer ", login.token))
+            .header("User-Agent", USER_AGENT)
+            .send()?,
+    )?;
+    Ok(login)
+}
+
+//Uploads any attachments and turns the email into a status,
+//returning it with the IDs of any media that's still processing
+fn draft_post(
+    login: &Login,
+    msg: &Message,
+    recipients: &[String],
+    mentions: &[String],
+) -> Result<(NewStatus, Vec<String>), ApiError> {
+    let account_url = login.url.clone();
+    let auth = string_concat!("Bearer ", login.token);
+    let mut status = msg.body_text(0).unwrap_or_default().to_string();
+    //We set the msg-id to the ID of the mastodon post, and this will
+    //be referenced in either the in-reply-to or references header.
+    //References has the whole thread, so the post being replied to is the last one
+    let mut reply_id = match (
+        msg.in_reply_to().as_text_list(),
+        msg.references().as_text_list(),
+    ) {
+        (Some(in_reply_to), _) => in_reply_to.first().copied().unwrap_or_default(),
+        (None, Some(references)) => references.last().copied().unwrap_or_default(),
+        (None, None) => "",
+    };
+
+    //Stolen from https://github.com/crisp-oss/email-reply-parser/lib/regex.js
+    //only supports English and certain email clients
+    //which I know is not good to use English as a default, but for a project of this scope,I think it's ok.
+    //If it's including the original message, disable including original replies in your email client
+    let reply_pattern = Regex::new(r"\s*>* On .* wrote:").unwrap();
+    if !reply_id.is_empty() {
+        if let Ok(Some(ind)) = reply_pattern.find(&status) {
+            println!("{:?}", ind);
+            status = status.split_at(ind.start()).0.to_string();
+        }
+        println!("status: {:?}", status);
+    }
+    //Strip whitespace and inline image markers from the end of status
+    status = status.replace('\u{FFFC}', "");
+    status = status.trim_end().to_string();
+    //Mention anyone it's addressed to who isn't already, or they won't see it
+    let mentions: Vec<String> = mentions
+        .iter()
+        .filter(|acct| !mentions_acct(&status, acct))
+        .map(|acct| string_concat!("@", acct))
+        .collect();
+    if !mentions.is_empty() {
+        status = format!("{} {}", mentions.join(" "), status);
+    }
+    //Some clients will add the domain to IDs, so strip that
+    if let Some((id, _)) = reply_id.rsplit_once('@') {
+        reply_id = id;
+    }
+    //Make an empty string vector
+    let mut media_ids = Vec::new();
+    //Big images and video come back before they're ready, without a URL
+    let mut processing = Vec::new();
+    let client = Client::new();
+    //We can only have 4 images per post, so the rest aren't uploaded
+    //Hint: if you want to DDOS a mastodon instance, look here :)
+    for attachment in msg
+        .attachments()
+        .filter(|attachment| !attachment.is_message())
+        .take(4)
+    {
+        //Get the attachment info out of the email
+        let content_type = attachment.content_type();
+        let bigtype = content_type.map(|ct| ct.ctype()).unwrap_or("image");
+        let subtype = content_type.and_then(|ct| ct.subtype()).unwrap_or("JPG");
+        let mime = string_concat!(bigtype, "/", subtype);
+        let name = attachment
+            .attachment_name()
+            .unwrap_or("Untitled.jpg")
+            .to_owned();
+        println!("Attachment Name: {:?}", name);
+        println!("Attachment Type: {:?}", mime);
+        //std::fs::write(attachment.attachment_name().unwrap_or("Untitled"), attachment.contents());
+        let content = attachment.contents().to_owned();
+
+        //Upload the image, we are given an ID in the reply which needs to be included in the post
+        let file_part = Part::bytes(content).file_name(name).mime_str(&mime)?;
+        let form = Form::new().part("file", file_part);
+        let uploaded: MediaAttachment = mastodon::parse(
+            client
+                .post(account_url.clone() + "/api/v2/media")
+                .header("Authorization", auth.clone())
+                .header("User-Agent", USER_AGENT)
+                .multipart(form)
+                .send()?,
+        )?;
+        if uploaded.url.is_none() {
+            processing.push(uploaded.id.clone());
+        }
+        media_ids.push(uploaded.id);
+    }
+    //Wrap the reply and convert to String
+    let in_reply_to_id = if reply_id.is_empty() {
+        None
+    } else {
+        Some(reply_id.to_string())
+    };
+    let form = NewStatus {
+        status,
+        in_reply_to_id,
+        media_ids,
+        visibility: visibility(recipients).map(str::to_string),
+    };
+    Ok((form, processing))
+}
+
+//Whether an account is the one posting, ex. "user" (local) or "user@example.com" for user@example.com
+fn is_sender(login: &Login, acct: &str) -> bool {
+    let (user, domain) = login
+        .address
+        .rsplit_once('@')
+        .unwrap_or((&login.address, ""));
+    match acct.split_once('@') {
+        Some((acct_user, acct_domain)) => {
+            acct_user.eq_ignore_ascii_case(user) && acct_domain.eq_ignore_ascii_case(domain)
+        }
+        None => acct.eq_ignore_ascii_case(user),
+    }
+}
+
+//Whether a post already mentions an account, ex. "@bob" or "@Bob." for bob, but not "@bobby"
+fn mentions_acct(status: &str, acct: &str) -> bool {
+    status
+        .split(|c: char| !(c.is_alphanumeric() || "@_.-".contains(c)))
+        .map(|word| word.trim_end_matches(['.', '-']))
+        .filter_map(|word| word.strip_prefix('@'))
+        .any(|mention| mention.eq_ignore_ascii_case(acct))
+}
+
+//Works out who can see a post from the RCPT TO addresses, ex. followers@mop3.
+//Sent only to fediverse accounts, it's a direct message.
+//Returns None if it's left to the account's default
+fn visibility(recipients: &[String]) -> Option<&'static str> {
+    let mut visibility = None;
+    let mut accounts = false;
+    for recipient in recipients {
+        match recipient.rsplit_once('@') {
+            Some((name, domain)) if domain.eq_ignore_ascii_case(VISIBILITY_DOMAIN) => {
+                //The most private one given wins
+                let named = match name.to_lowercase().as_str() {
+                    "public" => Some("public"),
+                    "unlisted" => Some("unlisted"),
+                    "followers" | "private" => Some("private"),
+                    "direct" => Some("direct"),
+                    _ => None,
                 };
-                let form = Status {
-                    status,
-                    in_reply_to_id,
-                    media_ids,
+                visibility = match (visibility, named) {
+                    (Some(current), Some(named)) => Some(most_private(current, named)),
+                    (current, named) => current.or(named),
                 };
-                println!(
-                    "{:?}",
-                    client
-                        .post(account_url + "/api/v1/statuses")
-                        .header("Authorization", auth.clone())
-                        .header("User-Agent", USER_AGENT)
-                        .json(&form)
-                        .send()
-                );
             }
-            SMTPCommand::Rset => {
-                from = "".to_string();
+            _ => accounts = true,
+        }
+    }
+    if visibility.is_none() && accounts {
+        visibility = Some("direct");
+    }
+    visibility
+}
+
+fn most_private<'a>(a: &'a str, b: &'a str) -> &'a str {
+    let rank = |visibility| {
+        ["public", "unlisted", "private", "direct"]
+            .iter()
+            .position(|v| *v == visibility)
+    };
+    if rank(a) >= rank(b) {
+        a
+    } else {
+        b
+    }
+}
+
+//Accounts from other servers have our domain on the end of their email address,
+//ex. friend@example.com@mastodon.social, so it comes off when there's two
+fn fediverse_acct(addr: &str) -> &str {
+    match addr.rsplit_once('@') {
+        Some((acct, _)) if acct.contains('@') => acct,
+        _ => addr,
+    }
+}
+
+//Looks up who an email address is on the fediverse, fetching them from their server if need be
+fn resolve_account(login: &Login, addr: &str) -> Result<Option<Account>, ApiError> {
+    let acct = fediverse_acct(addr);
+    let results: SearchResults = mastodon::parse(
+        Client::new()
+            .get(format!("{}/api/v2/search", login.url))
+            .query(&[
+                ("q", acct),
+                ("type", "accounts"),
+                ("resolve", "true"),
+                ("limit", "5"),
+            ])
+            .header("Authorization", string_concat!("Bearer ", login.token))
+            .header("User-Agent", USER_AGENT)
+            .send()?,
+    )?;
+    //Search is fuzzy, so make sure it's who was asked for. Accounts on our server have no domain
+    Ok(results.accounts.into_iter().find(|account| {
+        let full = if account.acct.contains('@') {
+            account.acct.clone()
+        } else {
+            format!("{}@{}", account.acct, login.domain)
+        };
+        full.eq_ignore_ascii_case(acct)
+    }))
+}
+
+//Posts a status once it's ready to go
+fn post_status(login: &Login, form: &NewStatus) -> Result<(), ApiError> {
+    let res = mastodon::check(
+        Client::new()
+            .post(login.url.clone() + "/api/v1/statuses")
+            .header("Authorization", string_concat!("Bearer ", login.token))
+            .header("User-Agent", USER_AGENT)
+            .json(form)
+            .send()?,
+    )?;
+    println!("{:?}", res);
+    Ok(())
+}
+
+//Waits for an upload to finish processing, which is when it gets a URL
+fn wait_for_media(login: &Login, id: &str) -> Result<(), ApiError> {
+    let client = Client::new();
+    for _ in 0..MEDIA_POLL_ATTEMPTS {
+        thread::sleep(MEDIA_POLL_INTERVAL);
+        let media: MediaAttachment = mastodon::parse(
+            client
+                .get(format!("{}/api/v1/media/{}", login.url, id))
+                .header("Authorization", string_concat!("Bearer ", login.token))
+                .header("User-Agent", USER_AGENT)
+                .send()?,
+        )?;
+        if media.url.is_some() {
+            return Ok(());
+        }
+    }
+    Err(ApiError::Temp(format!(
+        "Media {} still processing after {} seconds",
+        id,
+        (MEDIA_POLL_INTERVAL * MEDIA_POLL_ATTEMPTS).as_secs()
+    )))
+}
+
+//Posts once the media is ready, in the background, as the client has already been told it went through.
+//If it can't be posted, the email is bounced back to the account's mailbox instead.
+//Either way it's no longer pending
+fn post_later(post: &PendingPost, state: &Mutex<State>) {
+    let PendingPost {
+        id,
+        login,
+        form,
+        processing,
+        email,
+        recipients,
+    } = post;
+    let result = processing
+        .iter()
+        .try_for_each(|id| wait_for_media(login, id))
+        .and_then(|()| post_status(login, form));
+    let mut state = state.lock().unwrap();
+    state.remove_pending(id);
+    if let Err(err) = result {
+        println!("Could not post for {}, bouncing: {}", login.address, err);
+        let id = unique_id("bounce");
+        let email = bounce::bounce(&id, &login.address, recipients, email, &smtp_error(&err));
+        state.add_bounce(&login.address, Bounce { id, email });
+    }
+    if let Err(err) = state.save() {
+        println!("Could not save state: {:?}", err);
+    }
+}
+
+//Queues a post until its media is ready, saving it first so it isn't lost if MOP3 stops
+fn queue_post(post: PendingPost, state: &Arc<Mutex<State>>) {
+    {
+        let mut state = state.lock().unwrap();
+        state.add_pending(post.clone());
+        if let Err(err) = state.save() {
+            println!("Could not save state: {:?}", err);
+        }
+    }
+    let state = state.clone();
+    thread::spawn(move || post_later(&post, &state));
+}
+
+//An ID that won't come up again, ex. bounce.1700000000000000000
+fn unique_id(prefix: &str) -> String {
+    format!(
+        "{}.{}",
+        prefix,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or(0)
+    )
+}
+
+//SMTP reply for a post that didn't go through, with the server's reason, ex. going over the
+//character limit (422), a bad token (401), or being rate limited (429).
+//4xx means the client should try again later
+//For replies to RCPT and DATA. A refused token is a policy failure here, 535 is only for AUTH
+fn smtp_error(err: &ApiError) -> String {
+    match err {
+        ApiError::Auth(msg) => format!("550 5.7.1 {}\r\n", msg),
+        ApiError::Temp(msg) => format!("451 4.3.0 {}\r\n", msg),
+        ApiError::Perm(msg) => format!("554 5.0.0 {}\r\n", msg),
+    }
+}
+
+//Runs AUTH PLAIN, as in RFC 5034, getting the username and password.
+//The response can come on the AUTH line, otherwise we ask for it
+fn pop_auth(
+    stream: &mut Connection,
+    mechanism: Option<&str>,
+    initial: Option<&str>,
+) -> Option<Cred> {
+    let Some(mechanism) = mechanism else {
+        //Older clients (RFC 1734) ask for the list like this
+        send_str!(stream, "+OK\r\nPLAIN\r\n.\r\n");
+        return None;
+    };
+    if !mechanism.eq_ignore_ascii_case("PLAIN") {
+        send_str!(stream, "-ERR unsupported authentication mechanism\r\n");
+        return None;
+    }
+    let response = match initial {
+        Some(initial) => initial.to_string(),
+        None => {
+            send_str!(stream, "+ \r\n")?;
+            let mut response = String::new();
+            match stream.read_line(&mut response) {
+                Ok(len) if len > 0 => response,
+                _ => return None,
             }
-            SMTPCommand::Quit | SMTPCommand::Disconnect => return,
-            _ => (),
         }
+    };
+    let cred = sasl::decode_plain(&response);
+    if cred.is_none() {
+        send_str!(
+            stream,
+            "-ERR [AUTH] authentication cancelled or malformed\r\n"
+        );
+    }
+    cred
+}
+
+//Runs AUTH PLAIN or AUTH LOGIN, as in RFC 4954, getting the username and password.
+//Either can send its first response on the AUTH line, otherwise we ask for it
+fn smtp_auth(stream: &mut Connection, args: &str) -> Option<Cred> {
+    let mut args = args.split_whitespace();
+    let mechanism = args.next().unwrap_or_default().to_uppercase();
+    let initial = args.next().map(str::to_string);
+    let cred = match mechanism.as_str() {
+        "PLAIN" => initial
+            .or_else(|| smtp_challenge(stream, ""))
+            .and_then(|response| sasl::decode_plain(&response)),
+        "LOGIN" => initial
+            .or_else(|| smtp_challenge(stream, "Username:"))
+            .and_then(|response| sasl::decode(&response))
+            .and_then(|username| {
+                let password = sasl::decode(&smtp_challenge(stream, "Password:")?)?;
+                Some(Cred { username, password })
+            }),
+        _ => {
+            send_str!(stream, "504 5.5.4 Unrecognized authentication type\r\n");
+            return None;
+        }
+    };
+    if cred.is_none() {
+        send_str!(
+            stream,
+            "501 5.5.2 Authentication cancelled or malformed\r\n"
+        );
+    }
+    cred
+}
+
+//Sends a 334 challenge and reads back the response
+fn smtp_challenge(stream: &mut Connection, challenge: &str) -> Option<String> {
+    send_str!(stream, &format!("334 {}\r\n", sasl::encode(challenge)))?;
+    let mut response = String::new();
+    match stream.read_line(&mut response) {
+        Ok(len) if len > 0 => Some(response.trim_end().to_string()),
+        _ => None,
     }
 }
 
 //This is only used in POP3, basically a mini state machine that won't let you do anything before logging in
-fn get_login(stream: &mut TcpStream) -> Option<Cred> {
+fn get_login(config: &Config, stream: &mut Connection, timestamp: &str) -> Option<Cred> {
     let mut new_cred = Cred {
         username: String::new(),
         password: String::new(),
     };
     loop {
-        match get_pop_command(stream) {
+        let stls = config.tls.is_some() && !stream.is_tls();
+        //Passwords wait for TLS if it's there to be had (RFC 2595 section 2.1)
+        match get_pop_command(stream, stls, stls && !config.plaintextauth, false) {
             POPCommand::User(x) => new_cred.username = x,
             POPCommand::Pass(x) => new_cred.password = x,
-            POPCommand::Disconnect | POPCommand::Quit => return None,
+            POPCommand::Auth(Some(cred)) => return Some(cred),
+            //APOP never sends the secret, so it's filled in from the config file once the digest checks out
+            POPCommand::Apop { name, digest } => {
+                let (address, _, _) = strip_feed(&name);
+                match config.apop_secret(&address) {
+                    Some(secret) if sasl::check_apop(timestamp, secret, &digest) => {
+                        return Some(Cred {
+                            username: name,
+                            password: secret.to_string(),
+                        })
+                    }
+                    _ => send_str!(stream, "-ERR [AUTH] invalid APOP digest\r\n")?,
+                }
+            }
+            POPCommand::Stls => match &config.tls {
+                Some(tls) if stls => {
+                    send_str!(stream, "+OK Begin TLS negotiation\r\n")?;
+                    stream.start_tls(tls.clone()).ok()?;
+                    //Start over, a USER from before TLS can't be trusted (RFC 2595 section 4)
+                    new_cred.username.clear();
+                }
+                _ => send_str!(stream, "-ERR TLS not available\r\n")?,
+            },
+            POPCommand::Quit => {
+                send_str!(stream, "+OK bye\r\n");
+                return None;
+            }
+            POPCommand::Disconnect => return None,
+            POPCommand::Stat
+            | POPCommand::List(_)
+            | POPCommand::Retr(_)
+            | POPCommand::Dele(_)
+            | POPCommand::Rset
+            | POPCommand::Top { .. }
+            | POPCommand::Uidl(_) => {
+                send_str!(stream, "-ERR log in first\r\n");
+            }
             _ => (),
         }
         if !new_cred.username.is_empty() && !new_cred.password.is_empty() {
@@ -647,20 +1887,19 @@ fn get_login(stream: &mut TcpStream) -> Option<Cred> {
     }
 }
 
-//The JSON array wasn't given a struct bc I Am Lazy, so this is a helper function to get a string out of a JSON element
-fn get_str(element: &Value) -> &str {
-    element.as_str().unwrap_or_else(|| {
-        println!("Could not parse JSON element: {:?}", element);
-        ""
-    })
-}
-
-fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
-    let mut tcp_read = BufReader::new(stream.try_clone().unwrap());
+//stls is whether to tell CAPA that STLS works, which it only does before logging in without TLS,
+//and login_disabled whether USER, PASS and AUTH are refused until it's used. APOP is still fine.
+//logged_in refuses AUTH before its exchange starts, as there's no one to switch to
+fn get_pop_command(
+    stream: &mut Connection,
+    stls: bool,
+    login_disabled: bool,
+    logged_in: bool,
+) -> POPCommand {
     let mut cur_line = vec![];
     loop {
         cur_line.clear();
-        match tcp_read.read_until(b'\n', &mut cur_line) {
+        match stream.read_until(b'\n', &mut cur_line) {
             Ok(len) => {
                 if len == 0 {
                     println!("Socket closed");
@@ -677,6 +1916,9 @@ fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
         match split.next() {
             //USER or PASS shouldn't be received, bc we're already logged in here
             //but we handle it anyway because we're nice
+            Some("USER" | "PASS" | "AUTH") if login_disabled => {
+                send_str!(stream, "-ERR [AUTH] Must issue STLS first\r\n");
+            }
             Some("USER") => {
                 send_str!(stream, "+OK send PASS\r\n");
                 return POPCommand::User(split.next().unwrap_or("").to_string());
@@ -686,7 +1928,11 @@ fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
             Some("CAPA") => {
                 send_str!(
                     stream,
-                    "+OK Capability list follows\r\nUSER\r\nTOP\r\nUIDL\r\n.\r\n"
+                    &format!(
+                        "+OK Capability list follows\r\n{}TOP\r\nUIDL\r\nRESP-CODES\r\nAUTH-RESP-CODE\r\n{}.\r\n",
+                        if login_disabled { "LOGINDISABLED\r\n" } else { "USER\r\nSASL PLAIN\r\n" },
+                        if stls { "STLS\r\n" } else { "" }
+                    )
                 );
                 return POPCommand::Capa;
             }
@@ -697,13 +1943,12 @@ fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
                 send_str!(stream, "+OK\r\n");
                 return POPCommand::Noop;
             }
-            Some("RSET") => {
-                send_str!(stream, "+OK\r\n");
-                return POPCommand::Rset;
-            }
+            Some("RSET") => return POPCommand::Rset,
             Some("APOP") => {
-                send_str!(stream, "-ERR Server does not support APOP\r\n");
-                return POPCommand::Apop;
+                return POPCommand::Apop {
+                    name: split.next().unwrap_or("").to_string(),
+                    digest: split.next().unwrap_or("").to_string(),
+                }
             }
             //LIST (gives info about a message) and RETR (gives the message) can be sent with an index or not
             //so we either parse the index or return 0 (bc it's one indexed)
@@ -714,8 +1959,7 @@ fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
                 return POPCommand::Retr(split.next().unwrap_or("0").parse::<u32>().unwrap_or(0))
             }
             Some("DELE") => {
-                send_str!(stream, "+OK\r\n");
-                return POPCommand::Dele(split.next().unwrap_or("0").parse::<u32>().unwrap_or(0));
+                return POPCommand::Dele(split.next().unwrap_or("0").parse::<u32>().unwrap_or(0))
             }
             Some("UIDL") => {
                 return POPCommand::Uidl(split.next().unwrap_or("0").parse::<u32>().unwrap_or(0))
@@ -726,105 +1970,119 @@ fn get_pop_command(stream: &mut TcpStream) -> POPCommand {
                 let n = split.next().unwrap_or("0").parse::<u32>().unwrap_or(0);
                 return POPCommand::Top { msg, n };
             }
-            Some("AUTH") => {
-                send_str!(stream, "-ERR Server does not support AUTH\r\n");
-                return POPCommand::Auth;
+            Some("AUTH") if logged_in => {
+                send_str!(stream, "-ERR already logged in\r\n");
             }
+            Some("AUTH") => return POPCommand::Auth(pop_auth(stream, split.next(), split.next())),
+            Some("STLS") => return POPCommand::Stls,
             _ => (),
         }
     }
 }
 
-fn get_smtp_command(mut stream: TcpStream) -> SMTPCommand {
-    let re = Regex::new(r"(?<=\<)(.*?)(?=\>)").unwrap();
-    let mut tcp_read = BufReader::new(stream.try_clone().unwrap());
+//Reads one command, leaving the replies to the session, which knows what's allowed when
+fn get_smtp_command(stream: &mut Connection) -> SMTPCommand {
     let mut cur_line_bytes = vec![];
+    match stream.read_until(b'\n', &mut cur_line_bytes) {
+        Ok(0) => {
+            println!("Socket closed");
+            return SMTPCommand::Disconnect;
+        }
+        Ok(_) => (),
+        Err(err) => {
+            println!("TCP error: {:?}", err);
+            return SMTPCommand::Disconnect;
+        }
+    }
+    let cur_line = String::from_utf8_lossy(&cur_line_bytes);
+    let cur_line = cur_line.trim_end_matches(['\r', '\n']);
+    let (verb, args) = cur_line.split_once(' ').unwrap_or((cur_line, ""));
+    //Verbs aren't case sensitive (RFC 5321 section 2.4)
+    match verb.to_uppercase().as_str() {
+        "HELO" if args.trim().is_empty() => SMTPCommand::Invalid("HELO domain"),
+        "EHLO" if args.trim().is_empty() => SMTPCommand::Invalid("EHLO domain"),
+        "HELO" => SMTPCommand::Helo,
+        "EHLO" => SMTPCommand::Ehlo,
+        "MAIL" => match smtp_path(args, "FROM:") {
+            Some(_) => SMTPCommand::Mailfrom,
+            None => SMTPCommand::Invalid("MAIL FROM:<address>"),
+        },
+        //Unlike MAIL FROM, RCPT TO has to have someone in it
+        "RCPT" => match smtp_path(args, "TO:") {
+            Some(addr) if !addr.is_empty() => SMTPCommand::RcptTo(addr),
+            _ => SMTPCommand::Invalid("RCPT TO:<address>"),
+        },
+        "DATA" => SMTPCommand::Data,
+        //Not 504, as there's no mechanism to be unrecognized (RFC 4954 section 4)
+        "AUTH" if args.trim().is_empty() => {
+            SMTPCommand::Invalid("AUTH mechanism [initial-response]")
+        }
+        "AUTH" => SMTPCommand::Auth(args.to_string()),
+        "STARTTLS" => SMTPCommand::StartTls,
+        "NOOP" => SMTPCommand::Noop,
+        "RSET" => SMTPCommand::Rset,
+        "QUIT" => SMTPCommand::Quit,
+        _ => SMTPCommand::Unknown,
+    }
+}
+
+//Pulls the address out of MAIL FROM or RCPT TO, ex. "FROM:<user@example.com> SIZE=100".
+//Clients that leave out the brackets, or put a space after the colon, are let off.
+//"<>" is an empty address, which MAIL FROM allows for bounces
+fn smtp_path(args: &str, keyword: &str) -> Option<String> {
+    let prefix = args.get(..keyword.len())?;
+    if !prefix.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let path = args[keyword.len()..].trim_start();
+    let addr = match path.strip_prefix('<') {
+        Some(path) => path.split_once('>')?.0,
+        None => path.split_whitespace().next()?,
+    };
+    //Source routes (RFC 5321 section 4.1.2) are obsolete, but still have to be ignored
+    let addr = addr.rsplit_once(':').map_or(addr, |(_, addr)| addr);
+    Some(addr.trim().to_string())
+}
+
+//Reads a message after DATA, up to the line with just a dot.
+//Lines starting with a dot had another one added to them (RFC 5321 section 4.5.2), which comes off
+fn get_smtp_data(stream: &mut Connection) -> Option<String> {
+    let mut data = vec![];
+    let mut line = vec![];
     loop {
-        cur_line_bytes.clear();
-        match tcp_read.read_until(b'\n', &mut cur_line_bytes) {
-            Ok(len) => {
-                if len == 0 {
-                    println!("Socket closed");
-                    return SMTPCommand::Disconnect;
-                }
+        line.clear();
+        match stream.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                println!("Socket closed");
+                return None;
             }
+            Ok(_) => (),
             Err(err) => {
                 println!("TCP error: {:?}", err);
-                return SMTPCommand::Disconnect;
-            }
-        }
-        let cur_line = String::from_utf8_lossy(&cur_line_bytes);
-        if cur_line.starts_with("MAIL FROM:") {
-            send_str!(stream, "250 OK\r\n");
-            return SMTPCommand::Mailfrom(
-                re.captures(&cur_line)
-                    .unwrap()
-                    .unwrap()
-                    .get(0)
-                    .unwrap()
-                    .as_str()
-                    .to_string(),
-            );
-        } else if cur_line.starts_with("RCPT TO:") {
-            send_str!(stream, "250 OK\r\n");
-            return SMTPCommand::RcptTo(
-                re.captures(&cur_line)
-                    .unwrap()
-                    .unwrap()
-                    .get(0)
-                    .unwrap()
-                    .as_str()
-                    .to_string(),
-            );
-        } else if cur_line.starts_with("DATA") {
-            send_str!(stream, "354 Send message content\r\n");
-            let mut ret = "".to_string();
-            let mut data_cur_line_bytes = vec![];
-            //This isn't very clean, and basically is another state machine within this one
-            //but I don't /particularly/ care rn
-            loop {
-                match tcp_read.read_until(b'\n', &mut data_cur_line_bytes) {
-                    Ok(len) => {
-                        if len == 0 {
-                            println!("Socket closed");
-                            return SMTPCommand::Disconnect;
-                        }
-                    }
-                    Err(err) => {
-                        println!("TCP error: {:?}", err);
-                        return SMTPCommand::Disconnect;
-                    }
-                }
-                let cur_line = String::from_utf8_lossy(&data_cur_line_bytes);
-                //single dot represents the end of a message
-                if cur_line == ".\r\n" {
-                    break;
-                } else {
-                    ret = string_concat!(ret, cur_line);
-                }
-                data_cur_line_bytes.clear();
-            }
-            send_str!(stream, "250 OK\r\n");
-            return SMTPCommand::Data(ret);
-        } else if cur_line.starts_with("HELO") {
-            send_str!(stream, "250 mop3 whats poppin\r\n");
-            return SMTPCommand::Helo;
-        } else if cur_line.starts_with("EHLO") {
-            send_str!(
-                stream,
-                "250-mop3 whats poppin\r\n250-SIZE 5000000\r\n250 OK\r\n"
-            );
-            return SMTPCommand::Ehlo;
-        } else if cur_line.starts_with("NOOP") {
-            send_str!(stream, "250 OK\r\n");
-            return SMTPCommand::Noop;
-        } else if cur_line.starts_with("QUIT") {
-            send_str!(stream, "221 good bye\r\n");
-            return SMTPCommand::Quit;
-        } else if cur_line.starts_with("RSET") {
-            send_str!(stream, "250 OK\r\n");
-            return SMTPCommand::Rset;
+                return None;
+            }
+        }
+        if line == b".\r\n" || line == b".\n" {
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+        data.extend_from_slice(line.strip_prefix(b".").unwrap_or(&line));
+    }
+}
+
+//Splits the feed tag out of a POP3 username, ex. user+list.1234@example.com
+//returns the plain username, the tag, and the feed it names (None if it's not a feed we know)
+fn strip_feed(username: &str) -> (String, String, Option<Feed>) {
+    let (user, domain) = username.rsplit_once('@').unwrap_or((username, ""));
+    match user.split_once('+') {
+        Some((user, tag)) => {
+            let username = if domain.is_empty() {
+                user.to_string()
+            } else {
+                format!("{user}@{domain}")
+            };
+            (username, tag.to_string(), Feed::parse(tag))
         }
+        None => (username.to_string(), "home".to_string(), Some(Feed::Home)),
     }
 }
 
@@ -856,4 +2114,376 @@ mod tests {
             ("example.com".to_string(), "https://example.com".to_string())
         )
     }
+
+    #[test]
+    fn test_smtp_path() {
+        assert_eq!(
+            smtp_path("FROM:<user@example.com> SIZE=100", "FROM:").unwrap(),
+            "user@example.com"
+        );
+        assert_eq!(
+            smtp_path("to: user@example.com", "TO:").unwrap(),
+            "user@example.com"
+        );
+        assert_eq!(
+            smtp_path("TO:<@relay.example.com:user@example.com>", "TO:").unwrap(),
+            "user@example.com"
+        );
+        assert_eq!(smtp_path("FROM:<>", "FROM:").unwrap(), "");
+        assert!(smtp_path("FROM:<user@example.com", "FROM:").is_none());
+        assert!(smtp_path("FROM:", "FROM:").is_none());
+        assert!(smtp_path("user@example.com", "FROM:").is_none());
+    }
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(
+            dot_stuff(".\r\nHi\r\n.\r\n..\r\n"),
+            "..\r\nHi\r\n..\r\n...\r\n"
+        );
+        assert_eq!(dot_stuff("Hi\r\n"), "Hi\r\n");
+    }
+
+    #[test]
+    fn test_visibility() {
+        let recipients = |addrs: &[&str]| addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            visibility(&recipients(&["Unlisted@MOP3"])),
+            Some("unlisted")
+        );
+        assert_eq!(
+            visibility(&recipients(&["public@mop3", "followers@mop3"])),
+            Some("private")
+        );
+        assert_eq!(visibility(&recipients(&["post@mop3"])), None);
+        assert_eq!(
+            visibility(&recipients(&["friend@example.com"])),
+            Some("direct")
+        );
+        //Only mentioned in a public post, as the visibility was given
+        assert_eq!(
+            visibility(&recipients(&["friend@example.com", "public@mop3"])),
+            Some("public")
+        );
+    }
+
+    #[test]
+    fn test_is_sender() {
+        let login = Login {
+            address: "user@example.com".to_string(),
+            domain: "example.com".to_string(),
+            url: "https://example.com".to_string(),
+            token: "secret".to_string(),
+        };
+        assert!(is_sender(&login, "user"));
+        assert!(is_sender(&login, "User@Example.com"));
+        assert!(!is_sender(&login, "user@example.org"));
+        assert!(!is_sender(&login, "friend"));
+    }
+
+    #[test]
+    fn test_mentions_acct() {
+        assert!(mentions_acct("@bob hi", "bob"));
+        assert!(mentions_acct("Hi @Bob.", "bob"));
+        assert!(mentions_acct("(@bob@example.org)", "bob@example.org"));
+        assert!(!mentions_acct("@bobby hi", "bob"));
+        assert!(!mentions_acct("@bob@example.org hi", "bob"));
+        assert!(!mentions_acct("bob@example.org", "bob@example.org"));
+    }
+
+    #[test]
+    fn test_fediverse_acct() {
+        assert_eq!(
+            fediverse_acct("friend@example.com@mastodon.social"),
+            "friend@example.com"
+        );
+        assert_eq!(fediverse_acct("friend@example.com"), "friend@example.com");
+    }
+
+    #[test]
+    fn test_merge_window() {
+        let post = |id: &str| Item::Status(mastodon::tests::status(id, None));
+        let ids = |items: &[Item]| {
+            items
+                .iter()
+                .map(|item| item.id().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut mailbox = MailboxState {
+            deleted: vec!["3".to_string()],
+            window: vec![post("4"), post("3"), post("2"), post("1")],
+            ..Default::default()
+        };
+        //5 was fetched again, 3 was deleted, and 1 falls out of the window
+        let timeline = merge_window(vec![post("6"), post("5"), post("4")], &mut mailbox, 4);
+        assert_eq!(ids(&timeline), ["6", "5", "4", "2"]);
+        assert_eq!(ids(&mailbox.window), ["6", "5", "4", "2"]);
+        //A catch up bigger than the window is all kept
+        let timeline = merge_window(vec![post("9"), post("8"), post("7")], &mut mailbox, 2);
+        assert_eq!(ids(&timeline), ["9", "8", "7"]);
+    }
+
+    #[test]
+    fn test_notification_email() {
+        let status = mastodon::tests::status("10", None);
+        let notification = |kind: &str, status: Option<&Status>| -> Notification {
+            serde_json::from_value(serde_json::json!({
+                "id": "20",
+                "type": kind,
+                "created_at": "2023-04-02T12:00:00.000Z",
+                "account": {
+                    "id": "2",
+                    "username": "friend",
+                    "acct": "friend@example.org",
+                    "display_name": "Friend",
+                    "note": "<p>Hi there</p>",
+                    "url": "https://example.org/@friend"
+                },
+                "status": status
+            }))
+            .unwrap()
+        };
+
+        //Mentions are the post itself, from whoever mentioned us
+        let mention = notification("mention", Some(&status));
+        let email = notification_email(&mention);
+        assert_eq!(email.subject, "Mention from Friend");
+        assert_eq!(email.message_id, "10");
+        assert_eq!(email.from.acct, "friend@example.org");
+        assert_eq!(email.in_reply_to, None);
+
+        //Favourites are threaded under the post that was favourited
+        let favourite = notification("favourite", Some(&status));
+        let email = notification_email(&favourite);
+        assert_eq!(email.subject, "Friend favourited your post");
+        assert_eq!(email.message_id, "notification.20");
+        assert_eq!(email.in_reply_to, Some("10"));
+        assert_eq!(email.created_at, "2023-04-02T12:00:00.000Z");
+
+        //Follows have no post, so they say who it is
+        let follow = notification("follow", None);
+        let email = notification_email(&follow);
+        assert_eq!(email.subject, "Friend followed you");
+        assert_eq!(email.message_id, "notification.20");
+        assert!(email.content.contains("(@friend@example.org) followed you"));
+        assert!(email.content.contains("Hi there"));
+        assert_eq!(email.url, "https://example.org/@friend");
+
+        let unknown = notification("admin.sign_up", None);
+        assert_eq!(
+            notification_email(&unknown).subject,
+            "Notification from Friend"
+        );
+    }
+
+    #[test]
+    fn test_base64_size() {
+        assert_eq!(base64_size(0), 0);
+        assert_eq!(base64_size(3), 6);
+        assert_eq!(base64_size(57), 78);
+        assert_eq!(base64_size(58), 84);
+    }
+
+    #[test]
+    fn test_maildrop() {
+        //Serves a 3000 byte image to HEAD and GET, so sizes can be checked without Mastodon
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let image_url = format!("http://{}/image.png", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                //Skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let mut response = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 3000\r\nConnection: close\r\n\r\n".to_vec();
+                if request.starts_with("GET") {
+                    response.extend([7; 3000]);
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        let options = Options {
+            ascii: false,
+            attachment: true,
+            inline: false,
+            html: false,
+            url: false,
+            proxy: None,
+            window: 40,
+            maxfetch: 400,
+            ancestors: false,
+        };
+        let fetcher = Fetcher::new(1, None);
+        let plain = mastodon::tests::status("1", None);
+        let mut pictured = mastodon::tests::status("2", None);
+        pictured.media_attachments = vec![serde_json::from_value(serde_json::json!({
+            "id": "3",
+            "type": "image",
+            "url": image_url,
+            "description": null
+        }))
+        .unwrap()];
+        let emails = vec![post_email(&plain), post_email(&pictured)];
+        let to = ("User", "user@example.com");
+        let mut maildrop = Maildrop::new(&options, &fetcher, "example.com", to, emails);
+        maildrop.add_rendered("Subject: Bounce\r\n\r\nHi\r\n".to_string());
+
+        //Posts without images are rendered as soon as their size is asked for
+        let size = maildrop.size(0);
+        assert!(maildrop.rendered[0].is_some());
+        assert_eq!(size, maildrop.message(0).len());
+
+        //Posts with images are only estimated, counting the image
+        let estimate = maildrop.size(1);
+        assert!(maildrop.rendered[1].is_none());
+        assert_eq!(maildrop.estimates[1], Some(estimate));
+        assert!(estimate > base64_size(3000));
+
+        //and rendered for real when they're fetched, after which the size is exact
+        let len = maildrop.message(1).len();
+        assert!(maildrop.rendered[1].is_some());
+        assert_eq!(maildrop.size(1), len);
+        assert!(
+            estimate.abs_diff(len) < 300,
+            "estimated {estimate}, was {len}"
+        );
+
+        assert_eq!(maildrop.sizes(), [size, len, 23]);
+    }
+
+    #[test]
+    fn test_login_disabled() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::plain(listener.accept().unwrap().0);
+        client
+            .write_all(b"CAPA\r\nUSER user@example.com\r\nPASS secret\r\nSTLS\r\n")
+            .unwrap();
+        assert!(matches!(
+            get_pop_command(&mut stream, true, true, false),
+            POPCommand::Capa
+        ));
+        //USER and PASS are answered and skipped over, without ever being returned
+        assert!(matches!(
+            get_pop_command(&mut stream, true, true, false),
+            POPCommand::Stls
+        ));
+        drop(stream);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!(
+            replies,
+            "+OK Capability list follows\r\nLOGINDISABLED\r\nTOP\r\nUIDL\r\nRESP-CODES\r\nAUTH-RESP-CODE\r\nSTLS\r\n.\r\n\
+             -ERR [AUTH] Must issue STLS first\r\n\
+             -ERR [AUTH] Must issue STLS first\r\n"
+        );
+    }
+
+    #[test]
+    fn test_smtp_error() {
+        assert_eq!(
+            smtp_error(&ApiError::Auth("Server replied 401".to_string())),
+            "550 5.7.1 Server replied 401\r\n"
+        );
+        assert_eq!(
+            smtp_error(&ApiError::Temp("Server replied 503".to_string())),
+            "451 4.3.0 Server replied 503\r\n"
+        );
+    }
+
+    #[test]
+    fn test_auth_logged_in() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::plain(listener.accept().unwrap().0);
+        client.write_all(b"AUTH PLAIN\r\nQUIT\r\n").unwrap();
+        //Refused straight away, without asking for credentials
+        assert!(matches!(
+            get_pop_command(&mut stream, false, false, true),
+            POPCommand::Quit
+        ));
+        drop(stream);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "-ERR already logged in\r\n");
+    }
+
+    #[test]
+    fn test_smtp_syntax() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::plain(listener.accept().unwrap().0);
+        client.write_all(b"EHLO\r\nhelo\r\nAUTH \r\n").unwrap();
+        let syntax = |command| match command {
+            SMTPCommand::Invalid(syntax) => syntax,
+            _ => "",
+        };
+        assert_eq!(syntax(get_smtp_command(&mut stream)), "EHLO domain");
+        assert_eq!(syntax(get_smtp_command(&mut stream)), "HELO domain");
+        assert_eq!(
+            syntax(get_smtp_command(&mut stream)),
+            "AUTH mechanism [initial-response]"
+        );
+    }
+
+    #[test]
+    fn test_strip_feed() {
+        assert_eq!(
+            strip_feed("user@example.com"),
+            (
+                "user@example.com".to_string(),
+                "home".to_string(),
+                Some(Feed::Home)
+            )
+        );
+        assert_eq!(
+            strip_feed("user+list.1234@example.com"),
+            (
+                "user@example.com".to_string(),
+                "list.1234".to_string(),
+                Some(Feed::List("1234".to_string()))
+            )
+        );
+        assert_eq!(
+            strip_feed("user+tag.rust@example.com").2,
+            Some(Feed::Tag("rust".to_string()))
+        );
+        assert_eq!(strip_feed("user+local@example.com").2, Some(Feed::Local));
+        assert_eq!(
+            strip_feed("user+notifications@example.com").2,
+            Some(Feed::Notifications)
+        );
+        assert_eq!(strip_feed("user+direct@example.com").2, Some(Feed::Direct));
+        assert_eq!(strip_feed("user+nonsense@example.com").2, None);
+    }
+
+    #[test]
+    fn test_thread_references() {
+        let posts = vec![
+            Item::Status(mastodon::tests::status("3", Some("2"))),
+            Item::Status(mastodon::tests::status("2", Some("1"))),
+            Item::Status(mastodon::tests::status("1", None)),
+        ];
+        assert_eq!(
+            thread_references(posts[0].status().unwrap(), &posts),
+            vec!["1", "2"]
+        );
+        assert!(thread_references(posts[2].status().unwrap(), &posts).is_empty());
+    }
+
+    #[test]
+    fn test_deleted_messages() {
+        let sizes = vec![1, 2, 3];
+        let deleted = vec![false, true, false];
+        assert_eq!(msg_index(0, &deleted), None);
+        assert_eq!(msg_index(1, &deleted), Some(0));
+        assert_eq!(msg_index(2, &deleted), None);
+        assert_eq!(msg_index(4, &deleted), None);
+        assert_eq!(maildrop_size(&sizes, &deleted), (2, 4));
+    }
 }
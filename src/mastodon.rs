@@ -0,0 +1,367 @@
+//! The parts of the Mastodon API entities MOP3 uses
+//!
+//! <https://docs.joinmastodon.org/entities/>
+//!
+//! Fields the docs say are always there are required, so if the API changes
+//! underneath us we get an error naming the field, not an empty email.
+
+use std::fmt;
+
+use reqwest::{blocking::Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// <https://docs.joinmastodon.org/entities/Status/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Status {
+    pub id: String,
+    pub created_at: String,
+    pub in_reply_to_id: Option<String>,
+    pub account: Account,
+    pub content: String,
+    #[serde(default)]
+    pub spoiler_text: String,
+    pub visibility: String,
+    pub url: Option<String>,
+    pub reblog: Option<Box<Status>>,
+    pub media_attachments: Vec<MediaAttachment>,
+    pub mentions: Vec<Mention>,
+    pub tags: Vec<Tag>,
+    pub poll: Option<Poll>,
+    pub card: Option<Card>,
+}
+
+/// <https://docs.joinmastodon.org/entities/Account/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub acct: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub note: String,
+    pub url: String,
+}
+
+/// <https://docs.joinmastodon.org/entities/MediaAttachment/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaAttachment {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Null while the server is still processing an upload
+    pub url: Option<String>,
+    pub description: Option<String>,
+}
+
+/// <https://docs.joinmastodon.org/entities/Poll/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Poll {
+    pub id: String,
+    pub expired: bool,
+    pub multiple: bool,
+    pub votes_count: u64,
+    pub options: Vec<PollOption>,
+}
+
+/// <https://docs.joinmastodon.org/entities/Poll/#Option>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PollOption {
+    pub title: String,
+    /// Null if results aren't published yet
+    pub votes_count: Option<u64>,
+}
+
+/// Link preview
+///
+/// <https://docs.joinmastodon.org/entities/PreviewCard/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Card {
+    pub url: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// <https://docs.joinmastodon.org/entities/Notification/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub created_at: String,
+    pub account: Account,
+    pub status: Option<Status>,
+}
+
+/// A direct message thread
+///
+/// <https://docs.joinmastodon.org/entities/Conversation/>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conversation {
+    pub id: String,
+    pub unread: bool,
+    pub accounts: Vec<Account>,
+    pub last_status: Option<Status>,
+}
+
+/// <https://docs.joinmastodon.org/entities/Status/#Tag>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tag {
+    pub name: String,
+    pub url: String,
+}
+
+/// <https://docs.joinmastodon.org/entities/Status/#Mention>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mention {
+    pub id: String,
+    pub username: String,
+    pub url: String,
+    pub acct: String,
+}
+
+/// <https://docs.joinmastodon.org/entities/Context/>
+#[derive(Deserialize, Debug)]
+pub struct Context {
+    pub ancestors: Vec<Status>,
+    pub descendants: Vec<Status>,
+}
+
+/// Only accounts are searched for
+///
+/// <https://docs.joinmastodon.org/entities/Search/>
+#[derive(Deserialize, Debug)]
+pub struct SearchResults {
+    pub accounts: Vec<Account>,
+}
+
+/// An app registered to log in with, as returned when it's created
+///
+/// <https://docs.joinmastodon.org/entities/Application/#CredentialApplication>
+#[derive(Deserialize, Debug)]
+pub struct Application {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// <https://docs.joinmastodon.org/entities/Token/>
+#[derive(Deserialize, Debug)]
+pub struct Token {
+    pub access_token: String,
+    #[serde(default)]
+    pub scope: String,
+}
+
+/// What the server says went wrong, ex. "Validation failed: Text character limit of 500 exceeded"
+///
+/// <https://docs.joinmastodon.org/entities/Error/>
+#[derive(Deserialize, Debug)]
+pub struct Error {
+    pub error: String,
+}
+
+/// Form sent to post a status, kept in the state file while its media is processing
+///
+/// <https://docs.joinmastodon.org/methods/statuses/#create>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NewStatus {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub media_ids: Vec<String>,
+    /// public, unlisted, private (followers only) or direct, the account's default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<String>,
+}
+
+/// Something that ends up as one email in a mailbox
+///
+/// A mailbox is only ever one kind, so boxing to even the sizes out wouldn't save anything
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+pub enum Item {
+    Notification(Notification),
+    Status(Status),
+}
+
+impl Item {
+    pub fn id(&self) -> &str {
+        match self {
+            Item::Notification(notification) => &notification.id,
+            Item::Status(status) => &status.id,
+        }
+    }
+
+    pub fn status(&self) -> Option<&Status> {
+        match self {
+            Item::Notification(_) => None,
+            Item::Status(status) => Some(status),
+        }
+    }
+}
+
+/// Why a call to the Mastodon API failed, sorted by what the mail client should be told
+#[derive(Debug)]
+pub enum ApiError {
+    /// The token was refused
+    Auth(String),
+    /// Worth trying again later: the network, a 5xx, or rate limiting
+    Temp(String),
+    /// Anything else, including responses we couldn't make sense of
+    Perm(String),
+}
+
+impl ApiError {
+    fn from_status(status: StatusCode) -> ApiError {
+        ApiError::from_response(status, "")
+    }
+
+    /// Sorts an HTTP error status, keeping the server's explanation if the body has one
+    pub fn from_response(status: StatusCode, body: &str) -> ApiError {
+        let msg = match serde_json::from_str::<Error>(body) {
+            Ok(err) => format!("Server replied {status}: {}", err.error),
+            Err(_) => format!("Server replied {status}"),
+        };
+        match status.as_u16() {
+            401 | 403 => ApiError::Auth(msg),
+            429 | 500.. => ApiError::Temp(msg),
+            _ => ApiError::Perm(msg),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Auth(msg) | ApiError::Temp(msg) | ApiError::Perm(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(err: reqwest::Error) -> ApiError {
+        if let Some(status) = err.status() {
+            ApiError::from_status(status)
+        } else if err.is_decode() || err.is_builder() {
+            ApiError::Perm(err.to_string())
+        } else {
+            ApiError::Temp(format!("Could not reach server: {err}"))
+        }
+    }
+}
+
+/// Parses a response body, or if the status is an error, turns it into an ApiError.
+/// Shared by the blocking and async clients, which only differ in how they read the body
+pub fn decode<T: DeserializeOwned>(status: StatusCode, body: &str) -> Result<T, ApiError> {
+    if !status.is_success() {
+        return Err(ApiError::from_response(status, body));
+    }
+    serde_json::from_str(body)
+        .map_err(|err| ApiError::Perm(format!("Server sent malformed JSON: {err}")))
+}
+
+/// Turns HTTP error statuses into an ApiError, with the error the server sent back
+pub fn check(res: Response) -> Result<Response, ApiError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    Err(ApiError::from_response(
+        status,
+        &res.text().unwrap_or_default(),
+    ))
+}
+
+/// Checks the response and parses it, saying which field was wrong if it doesn't parse
+pub fn parse<T: DeserializeOwned>(res: Response) -> Result<T, ApiError> {
+    let status = res.status();
+    decode(status, &res.text()?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn status(id: &str, in_reply_to_id: Option<&str>) -> Status {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "created_at": "2023-04-01T12:00:00.000Z",
+            "in_reply_to_id": in_reply_to_id,
+            "account": {
+                "id": "1",
+                "username": "user",
+                "acct": "user",
+                "display_name": "User",
+                "url": "https://example.com/@user"
+            },
+            "content": "<p>Hello</p>",
+            "visibility": "public",
+            "url": null,
+            "reblog": null,
+            "media_attachments": [],
+            "mentions": [],
+            "tags": [],
+            "poll": null,
+            "card": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_item_kinds() {
+        let post = serde_json::to_value(status("1", None)).unwrap();
+        let notification = serde_json::json!({
+            "id": "2",
+            "type": "favourite",
+            "created_at": "2023-04-01T12:00:00.000Z",
+            "account": post["account"],
+            "status": post
+        });
+        let item: Item = serde_json::from_value(post.clone()).unwrap();
+        assert!(item.status().is_some());
+        let item: Item = serde_json::from_value(notification).unwrap();
+        assert_eq!(item.id(), "2");
+        assert!(item.status().is_none());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let err = ApiError::from_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            r#"{"error": "Validation failed: Text character limit of 500 exceeded"}"#,
+        );
+        assert!(matches!(err, ApiError::Perm(_)));
+        assert_eq!(
+            err.to_string(),
+            "Server replied 422 Unprocessable Entity: Validation failed: Text character limit of 500 exceeded"
+        );
+        let err = ApiError::from_response(StatusCode::TOO_MANY_REQUESTS, "<html>Slow down</html>");
+        assert!(matches!(err, ApiError::Temp(_)));
+        assert_eq!(err.to_string(), "Server replied 429 Too Many Requests");
+    }
+
+    #[test]
+    fn test_decode() {
+        let err = decode::<Error>(
+            StatusCode::UNAUTHORIZED,
+            r#"{"error": "The access token is invalid"}"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ApiError::Auth(_)));
+        let err = decode::<Error>(StatusCode::OK, "<html>").unwrap_err();
+        assert!(matches!(err, ApiError::Perm(_)));
+        assert!(err.to_string().starts_with("Server sent malformed JSON"));
+        let ok: Error = decode(StatusCode::OK, r#"{"error": "fine"}"#).unwrap();
+        assert_eq!(ok.error, "fine");
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let mut post = serde_json::to_value(status("1", None)).unwrap();
+        post.as_object_mut().unwrap().remove("content");
+        let err = serde_json::from_value::<Status>(post).unwrap_err();
+        assert!(err.to_string().contains("content"));
+    }
+}
@@ -0,0 +1,81 @@
+//! Decoding the responses to SASL challenges, which both AUTH commands use, and checking APOP
+//!
+//! <https://www.rfc-editor.org/rfc/rfc4616> (PLAIN)
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+
+use crate::Cred;
+
+/// Decodes a base64 response, or None if it's cancelled ("*") or isn't base64
+pub fn decode(response: &str) -> Option<String> {
+    let response = response.trim();
+    if response == "*" {
+        return None;
+    }
+    String::from_utf8(STANDARD.decode(response).ok()?).ok()
+}
+
+/// Splits a PLAIN response into the username and password. We have no use for logging in
+/// as someone else, so the authorization identity is ignored
+pub fn decode_plain(response: &str) -> Option<Cred> {
+    let decoded = decode(response)?;
+    let mut parts = decoded.split('\0');
+    let (_authzid, username, password) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || username.is_empty() || password.is_empty() {
+        return None;
+    }
+    Some(Cred {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Encodes a challenge, ex. "Username:" for AUTH LOGIN
+pub fn encode(challenge: &str) -> String {
+    STANDARD.encode(challenge)
+}
+
+/// Checks an APOP digest, the MD5 of the greeting's timestamp followed by the shared secret
+///
+/// <https://www.rfc-editor.org/rfc/rfc1939#page-15>
+pub fn check_apop(timestamp: &str, secret: &str, digest: &str) -> bool {
+    let expected: String = Md5::digest(format!("{timestamp}{secret}"))
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    expected.eq_ignore_ascii_case(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_plain() {
+        let cred = decode_plain(&STANDARD.encode("\0user@example.com\0token")).unwrap();
+        assert_eq!(cred.username, "user@example.com");
+        assert_eq!(cred.password, "token");
+        assert!(decode_plain(&STANDARD.encode("user@example.com\0token")).is_none());
+        assert!(decode_plain("*").is_none());
+        assert!(decode_plain("not base64!").is_none());
+        assert_eq!(encode("Username:"), "VXNlcm5hbWU6");
+        assert_eq!(decode("UGFzc3dvcmQ6").unwrap(), "Password:");
+    }
+
+    #[test]
+    fn test_check_apop() {
+        //The example from RFC 1939
+        let timestamp = "<1896.697170952@dbc.mtview.ca.us>";
+        assert!(check_apop(
+            timestamp,
+            "tanstaaf",
+            "c4c9334bac560ecc979e58001b3e22fb"
+        ));
+        assert!(!check_apop(
+            timestamp,
+            "tanstaaf2",
+            "c4c9334bac560ecc979e58001b3e22fb"
+        ));
+    }
+}
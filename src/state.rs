@@ -0,0 +1,360 @@
+//! On-disk state shared between POP3 sessions
+//!
+//! Each mailbox is keyed by the Mastodon account and the client that fetched it,
+//! so two mail clients (or a restart) don't steal each other's posts.
+//! Sessions run on their own threads, so the state lives behind a Mutex.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Login;
+use crate::mastodon::{Item, NewStatus};
+
+/// How many deleted status IDs to remember per mailbox
+const DELETED_HISTORY: usize = 1000;
+/// How many bounces an account can have waiting, so a client that never deletes them doesn't fill up the state file
+const BOUNCE_HISTORY: usize = 20;
+/// How long a mailbox is kept after its client last connected, in seconds. Clients are told
+/// apart by IP, which can change (DHCP, IPv6 privacy addresses), leaving the old mailbox behind
+const MAILBOX_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+/// What we remember about one account as seen by one client
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct MailboxState {
+    /// Most recent status ID fetched, passed to the API as since_id
+    #[serde(default)]
+    pub recent_id: String,
+    /// Status IDs the client deleted, which should never be served again
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    /// The last few posts, newest first, so the mailbox doesn't empty out once read
+    #[serde(default)]
+    pub window: Vec<Item>,
+    /// When a session last saved this mailbox, in seconds since the epoch
+    #[serde(default)]
+    pub last_seen: u64,
+}
+
+impl MailboxState {
+    /// since_id only ever returns newer posts, so only recent deletions need remembering
+    pub fn forget_old_deletions(&mut self) {
+        if self.deleted.len() > DELETED_HISTORY {
+            self.deleted.drain(..self.deleted.len() - DELETED_HISTORY);
+        }
+    }
+}
+
+/// A post that failed after the client was told it went through, waiting in the account's mailbox
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Bounce {
+    /// Its UIDL
+    pub id: String,
+    pub email: String,
+}
+
+/// A post waiting on its media to finish processing, kept so a restart carries on with it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingPost {
+    pub id: String,
+    /// Who it's posted as, token and all
+    pub login: Login,
+    pub form: NewStatus,
+    /// IDs of the media still processing
+    pub processing: Vec<String>,
+    /// The email it came from, in case it has to be bounced
+    pub email: String,
+    pub recipients: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    mailboxes: HashMap<String, MailboxState>,
+    /// Keyed by account address, ex. "user@example.com", and kept until a client deletes them
+    #[serde(default)]
+    bounces: HashMap<String, Vec<Bounce>>,
+    /// Removed once they're posted or bounced
+    #[serde(default)]
+    pending: Vec<PendingPost>,
+    /// IDs of every post above a reply in its thread, oldest first, keyed by account address
+    /// then post ID. Shared by all of an account's mailboxes, so threads are only looked up once
+    #[serde(default)]
+    threads: HashMap<String, HashMap<String, Vec<String>>>,
+    /// Mailboxes with a session open on them right now
+    #[serde(skip)]
+    locked: HashSet<String>,
+}
+
+impl State {
+    /// Loads the state file, starting fresh if it doesn't exist yet
+    pub fn load(path: &Path) -> State {
+        let mut state = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                println!(
+                    "Could not parse state file {:?}, starting over: {}",
+                    path, err
+                );
+                State::default()
+            }),
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    println!("Could not read state file {:?}: {}", path, err);
+                }
+                State::default()
+            }
+        };
+        state.path = path.to_path_buf();
+        //Mailboxes from before last_seen was saved get a fresh start
+        for mailbox in state.mailboxes.values_mut() {
+            if mailbox.last_seen == 0 {
+                mailbox.last_seen = now();
+            }
+        }
+        state
+    }
+
+    /// Writes the state out, going through a temp file so a crash can't leave half a file.
+    /// Mailboxes no client has opened in a while are dropped first
+    pub fn save(&mut self) -> io::Result<()> {
+        self.forget_old_mailboxes();
+        self.forget_old_threads();
+        let json = serde_json::to_string_pretty(self)?;
+        let tmp = self.path.with_extension("tmp");
+        //Pending posts have tokens in them, so it's made private before they're written
+        fs::write(&tmp, "")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    pub fn mailbox(&self, key: &str) -> MailboxState {
+        self.mailboxes.get(key).cloned().unwrap_or_default()
+    }
+
+    pub fn set_mailbox(&mut self, key: &str, mut mailbox: MailboxState) {
+        mailbox.last_seen = now();
+        self.mailboxes.insert(key.to_string(), mailbox);
+    }
+
+    fn forget_old_mailboxes(&mut self) {
+        let cutoff = now().saturating_sub(MAILBOX_EXPIRY);
+        let locked = &self.locked;
+        self.mailboxes
+            .retain(|key, mailbox| mailbox.last_seen >= cutoff || locked.contains(key));
+    }
+
+    pub fn bounces(&self, address: &str) -> Vec<Bounce> {
+        self.bounces.get(address).cloned().unwrap_or_default()
+    }
+
+    pub fn add_bounce(&mut self, address: &str, bounce: Bounce) {
+        let bounces = self.bounces.entry(address.to_string()).or_default();
+        bounces.push(bounce);
+        if bounces.len() > BOUNCE_HISTORY {
+            bounces.drain(..bounces.len() - BOUNCE_HISTORY);
+        }
+    }
+
+    pub fn remove_bounces(&mut self, address: &str, ids: &[&str]) {
+        if let Some(bounces) = self.bounces.get_mut(address) {
+            bounces.retain(|bounce| !ids.contains(&bounce.id.as_str()));
+            if bounces.is_empty() {
+                self.bounces.remove(address);
+            }
+        }
+    }
+
+    pub fn threads(&self, address: &str) -> HashMap<String, Vec<String>> {
+        self.threads.get(address).cloned().unwrap_or_default()
+    }
+
+    pub fn add_threads(&mut self, address: &str, threads: HashMap<String, Vec<String>>) {
+        self.threads
+            .entry(address.to_string())
+            .or_default()
+            .extend(threads);
+    }
+
+    //Threads are only needed while their post is in a mailbox
+    fn forget_old_threads(&mut self) {
+        let ids: HashSet<&str> = self
+            .mailboxes
+            .values()
+            .flat_map(|mailbox| mailbox.window.iter().map(Item::id))
+            .collect();
+        for threads in self.threads.values_mut() {
+            threads.retain(|id, _| ids.contains(id.as_str()));
+        }
+        self.threads.retain(|_, threads| !threads.is_empty());
+    }
+
+    pub fn pending(&self) -> Vec<PendingPost> {
+        self.pending.clone()
+    }
+
+    pub fn add_pending(&mut self, post: PendingPost) {
+        self.pending.push(post);
+    }
+
+    pub fn remove_pending(&mut self, id: &str) {
+        self.pending.retain(|post| post.id != id);
+    }
+}
+
+/// Holds a mailbox for the length of a session, as RFC 1939 only lets one client
+/// have a maildrop at a time. Unlocks when dropped
+pub struct MailboxLock<'a> {
+    state: &'a Mutex<State>,
+    key: String,
+}
+
+impl Drop for MailboxLock<'_> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().locked.remove(&self.key);
+    }
+}
+
+/// Locks a mailbox, or returns None if another session already has it
+pub fn lock_mailbox<'a>(state: &'a Mutex<State>, key: &str) -> Option<MailboxLock<'a>> {
+    state
+        .lock()
+        .unwrap()
+        .locked
+        .insert(key.to_string())
+        .then(|| MailboxLock {
+            state,
+            key: key.to_string(),
+        })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the key a mailbox is stored under, ex. "user@example.com 192.168.1.20"
+pub fn mailbox_key(account_addr: &str, client: &str) -> String {
+    format!("{account_addr} {client}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let path = std::env::temp_dir().join(format!("mop3-state-{}.json", std::process::id()));
+        let mut state = State::load(&path);
+        let key = mailbox_key("user@example.com", "127.0.0.1");
+        assert_eq!(state.mailbox(&key).recent_id, "");
+        state.set_mailbox(
+            &key,
+            MailboxState {
+                recent_id: "1234".to_string(),
+                deleted: vec!["1233".to_string()],
+                window: vec![Item::Status(crate::mastodon::tests::status("1234", None))],
+                last_seen: 0,
+            },
+        );
+        //A client that hasn't been back in over a month
+        state.mailboxes.insert(
+            mailbox_key("user@example.com", "10.0.0.5"),
+            MailboxState {
+                last_seen: now() - MAILBOX_EXPIRY - 1,
+                ..MailboxState::default()
+            },
+        );
+        //1234 is in a mailbox, 1 isn't any more
+        state.add_threads(
+            "user@example.com",
+            HashMap::from([
+                ("1234".to_string(), vec!["1230".to_string()]),
+                ("1".to_string(), vec![]),
+            ]),
+        );
+        state.add_bounce(
+            "user@example.com",
+            Bounce {
+                id: "bounce.1".to_string(),
+                email: "Subject: Undelivered\r\n\r\n".to_string(),
+            },
+        );
+        state.add_pending(PendingPost {
+            id: "pending.1".to_string(),
+            login: Login {
+                address: "user@example.com".to_string(),
+                domain: "example.com".to_string(),
+                url: "https://example.com".to_string(),
+                token: "secret".to_string(),
+            },
+            form: NewStatus {
+                status: "Hello".to_string(),
+                in_reply_to_id: None,
+                media_ids: vec!["5".to_string()],
+                visibility: Some("unlisted".to_string()),
+            },
+            processing: vec!["5".to_string()],
+            email: "Subject: Hello\r\n\r\nHello\r\n".to_string(),
+            recipients: vec!["unlisted@mop3".to_string()],
+        });
+        state.save().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        let state = State::load(&path);
+        assert_eq!(state.mailbox(&key).recent_id, "1234");
+        assert_eq!(state.mailbox(&key).deleted, vec!["1233".to_string()]);
+        assert_eq!(state.mailbox(&key).window[0].id(), "1234");
+        assert_eq!(state.mailbox("other 127.0.0.1").recent_id, "");
+        assert!(state.mailbox(&key).last_seen > 0);
+        let threads = state.threads("user@example.com");
+        assert_eq!(threads["1234"], ["1230"]);
+        assert!(!threads.contains_key("1"));
+        assert!(state.threads("other@example.com").is_empty());
+        assert!(!state
+            .mailboxes
+            .contains_key(&mailbox_key("user@example.com", "10.0.0.5")));
+        let mut state = state;
+        assert_eq!(state.bounces("user@example.com")[0].id, "bounce.1");
+        state.remove_bounces("user@example.com", &["bounce.1"]);
+        assert!(state.bounces("user@example.com").is_empty());
+        let pending = state.pending();
+        assert_eq!(pending[0].login.token, "secret");
+        assert_eq!(pending[0].form.media_ids, ["5"]);
+        assert_eq!(pending[0].processing, ["5"]);
+        state.remove_pending("pending.1");
+        assert!(state.pending().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mailbox_lock() {
+        let state = Mutex::new(State::default());
+        let lock = lock_mailbox(&state, "user@example.com 127.0.0.1");
+        assert!(lock.is_some());
+        assert!(lock_mailbox(&state, "user@example.com 127.0.0.1").is_none());
+        assert!(lock_mailbox(&state, "user@example.com 127.0.0.2").is_some());
+        drop(lock);
+        assert!(lock_mailbox(&state, "user@example.com 127.0.0.1").is_some());
+    }
+}
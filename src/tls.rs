@@ -0,0 +1,230 @@
+//! TLS, for POP3S and SMTPS, and for upgrading plain connections with STLS and STARTTLS
+//!
+//! Every session talks to a Connection, which is either plain or TLS, so the protocol
+//! code doesn't have to care which one it got.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::config::Config;
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    /// Only while switching to TLS
+    Closed,
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Closed => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Closed => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Closed => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+/// A client connection, buffered for reading commands a line at a time
+pub struct Connection {
+    stream: BufReader<Stream>,
+    peer: io::Result<SocketAddr>,
+}
+
+impl Connection {
+    pub fn plain(stream: TcpStream) -> Connection {
+        Connection {
+            peer: stream.peer_addr(),
+            stream: BufReader::new(Stream::Plain(stream)),
+        }
+    }
+
+    /// For POP3S and SMTPS, where TLS starts straight away
+    pub fn tls(stream: TcpStream, tls: Arc<ServerConfig>) -> Result<Connection, rustls::Error> {
+        Ok(Connection {
+            peer: stream.peer_addr(),
+            stream: BufReader::new(Stream::Tls(Box::new(StreamOwned::new(
+                ServerConnection::new(tls)?,
+                stream,
+            )))),
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match &self.peer {
+            Ok(peer) => Ok(*peer),
+            Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.stream.get_ref(), Stream::Tls(_))
+    }
+
+    /// Switches to TLS after STLS or STARTTLS has been answered. Anything the client sent
+    /// after the command was sent in the clear, so it's thrown away (RFC 3207 section 4.2)
+    pub fn start_tls(&mut self, tls: Arc<ServerConfig>) -> io::Result<()> {
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Stream::Closed));
+        let Stream::Plain(stream) = stream.into_inner() else {
+            return Err(io::Error::other("already using TLS"));
+        };
+        let connection = ServerConnection::new(tls).map_err(io::Error::other)?;
+        self.stream = BufReader::new(Stream::Tls(Box::new(StreamOwned::new(connection, stream))));
+        Ok(())
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl BufRead for Connection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.stream.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.stream.consume(amt)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.get_mut().flush()
+    }
+}
+
+/// Loads the certificate and key from the config, making a self-signed pair first if asked to.
+/// None if TLS isn't set up
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert, &config.key) else {
+        return Ok(None);
+    };
+    if config.selfsigned && !cert_path.exists() && !key_path.exists() {
+        self_sign(&config.address, cert_path, key_path)
+            .map_err(|err| format!("Could not make self-signed certificate: {}", err))?;
+        println!(
+            "Made a self-signed certificate in {}, clients will ask you to trust it",
+            cert_path.display()
+        );
+    }
+    load(cert_path, key_path).map(|tls| Some(Arc::new(tls)))
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, String> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        fs::File::open(cert_path)
+            .map_err(|err| format!("Could not read {}: {}", cert_path.display(), err))?,
+    ))
+    .map_err(|err| format!("Could not read {}: {}", cert_path.display(), err))?
+    .into_iter()
+    .map(Certificate)
+    .collect();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        fs::File::open(key_path)
+            .map_err(|err| format!("Could not read {}: {}", key_path.display(), err))?,
+    ))
+    .map_err(|err| format!("Could not read {}: {}", key_path.display(), err))?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+        _ => None,
+    })
+    .ok_or_else(|| format!("No private key in {}", key_path.display()))?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Could not use certificate: {}", err))
+}
+
+//Makes a certificate for the address we're listening on, and localhost
+fn self_sign(address: &str, cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    let mut names = vec!["localhost".to_string()];
+    if address != "0.0.0.0" && address != "localhost" {
+        names.push(address.to_string());
+    }
+    let cert = rcgen::generate_simple_self_signed(names).map_err(|err| err.to_string())?;
+    fs::write(
+        cert_path,
+        cert.serialize_pem().map_err(|err| err.to_string())?,
+    )
+    .map_err(|err| err.to_string())?;
+    //The key is made private before it goes in, then moved into place
+    let tmp = key_path.with_extension("tmp");
+    fs::write(&tmp, "").map_err(|err| err.to_string())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))
+            .map_err(|err| err.to_string())?;
+    }
+    fs::write(&tmp, cert.serialize_private_key_pem()).map_err(|err| err.to_string())?;
+    fs::rename(tmp, key_path).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_start_tls() {
+        let dir = std::env::temp_dir().join(format!("mop3-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        self_sign("127.0.0.1", &cert, &key).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&key).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        let tls = Arc::new(load(&cert, &key).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connection = Connection::plain(listener.accept().unwrap().0);
+        assert!(!connection.is_tls());
+        connection.start_tls(tls.clone()).unwrap();
+        assert!(connection.is_tls());
+        assert!(connection.start_tls(tls).is_err());
+        drop(client);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}